@@ -0,0 +1,203 @@
+use std::fmt;
+
+/// Represents the failures that can occur while building,
+/// encoding or decoding an Ockam message
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageError {
+    /// The requested operation is not supported
+    NotImplemented,
+    /// The operation requires a route with at least one address
+    EmptyRoute,
+    /// A route has more addresses than can be encoded
+    RouteTooLong(usize),
+    /// A value is larger than its encoding allows
+    ValueTooLarge,
+    /// An unknown address type was found
+    UnknownAddressType(u8),
+    /// An unknown host address type was found
+    UnknownHostAddressType(u8),
+    /// The buffer ended before a complete value could be decoded
+    BufferTooShort {
+        /// How many bytes the value requires
+        needed: usize,
+        /// How many bytes were available
+        got: usize,
+    },
+    /// A route declares more addresses than the bytes after its count could hold
+    RouteTruncated {
+        /// How many addresses the route declares
+        count: u64,
+        /// How many bytes followed the count
+        got: usize,
+    },
+    /// A length-prefixed value was followed by bytes it doesn't account for
+    TrailingBytes(usize),
+    /// Tunnel hops are nested deeper than allowed
+    NestingTooDeep,
+    /// A message header has flags set that aren't understood
+    UnknownFlags(u8),
+    /// An address isn't allowed by the address policy in use
+    PolicyViolation(String),
+    /// A codec was registered for one of the built-in address types
+    ReservedAddressType(u8),
+    /// An address can't be used as a hop, for the given reason
+    InvalidAddress(String),
+    /// A local address is the unspecified zero address where a real worker is required
+    NullLocalAddress,
+    /// A text field isn't valid UTF-8
+    InvalidUtf8,
+    /// A frame uses a wire protocol version this implementation doesn't speak
+    UnsupportedVersion(u16),
+    /// An unknown content type was found
+    UnknownContentType(u8),
+    /// A message body is larger than its content type allows
+    BodyTooLarge {
+        /// The largest body allowed
+        max: usize,
+        /// The size of the body
+        got: usize,
+    },
+    /// A batch declares more messages than the decoder accepts
+    TooManyMessages(u64),
+    /// An optional value's presence byte is neither 0 nor 1
+    InvalidPresenceByte(u8),
+    /// A message or frame carries more than a body where only a body is expected
+    NotBodyOnly,
+    /// An unknown kind of route delta was found
+    UnknownRouteDelta(u8),
+    /// A message has no nonce where replay protection requires one
+    MissingNonce,
+    /// A message's nonce has been seen before
+    ReplayedNonce(u64),
+    /// A message's nonce is too far behind the newest to tell whether it was seen
+    StaleNonce(u64),
+    /// A frame's MAC doesn't match its contents under the given key
+    MacMismatch,
+    /// A message's text form can't be parsed, for the given reason
+    InvalidText(String),
+    /// An I/O operation failed with the given kind
+    Io(std::io::ErrorKind),
+}
+
+impl MessageError {
+    /// The name of the variant, as a stable label for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            MessageError::NotImplemented => "NotImplemented",
+            MessageError::EmptyRoute => "EmptyRoute",
+            MessageError::RouteTooLong(_) => "RouteTooLong",
+            MessageError::ValueTooLarge => "ValueTooLarge",
+            MessageError::UnknownAddressType(_) => "UnknownAddressType",
+            MessageError::UnknownHostAddressType(_) => "UnknownHostAddressType",
+            MessageError::BufferTooShort { .. } => "BufferTooShort",
+            MessageError::RouteTruncated { .. } => "RouteTruncated",
+            MessageError::TrailingBytes(_) => "TrailingBytes",
+            MessageError::NestingTooDeep => "NestingTooDeep",
+            MessageError::UnknownFlags(_) => "UnknownFlags",
+            MessageError::PolicyViolation(_) => "PolicyViolation",
+            MessageError::ReservedAddressType(_) => "ReservedAddressType",
+            MessageError::InvalidAddress(_) => "InvalidAddress",
+            MessageError::NullLocalAddress => "NullLocalAddress",
+            MessageError::InvalidUtf8 => "InvalidUtf8",
+            MessageError::UnsupportedVersion(_) => "UnsupportedVersion",
+            MessageError::UnknownContentType(_) => "UnknownContentType",
+            MessageError::BodyTooLarge { .. } => "BodyTooLarge",
+            MessageError::TooManyMessages(_) => "TooManyMessages",
+            MessageError::InvalidPresenceByte(_) => "InvalidPresenceByte",
+            MessageError::NotBodyOnly => "NotBodyOnly",
+            MessageError::UnknownRouteDelta(_) => "UnknownRouteDelta",
+            MessageError::MissingNonce => "MissingNonce",
+            MessageError::ReplayedNonce(_) => "ReplayedNonce",
+            MessageError::StaleNonce(_) => "StaleNonce",
+            MessageError::MacMismatch => "MacMismatch",
+            MessageError::InvalidText(_) => "InvalidText",
+            MessageError::Io(_) => "Io",
+        }
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::NotImplemented => write!(f, "The requested feature is not supported"),
+            MessageError::EmptyRoute => write!(f, "The route has no addresses"),
+            MessageError::RouteTooLong(n) => write!(f, "The route has too many addresses: {}", n),
+            MessageError::ValueTooLarge => write!(f, "Maximum value exceeded"),
+            MessageError::UnknownAddressType(t) => write!(f, "Unknown address type: {}", t),
+            MessageError::UnknownHostAddressType(t) => {
+                write!(f, "Unknown host address type: {}", t)
+            }
+            MessageError::BufferTooShort { needed, got } => write!(
+                f,
+                "The buffer is too short. Needed: {} bytes, got: {}",
+                needed, got
+            ),
+            MessageError::RouteTruncated { count, got } => write!(
+                f,
+                "The route declares {} addresses but only {} bytes follow",
+                count, got
+            ),
+            MessageError::TrailingBytes(n) => write!(f, "Found {} unexpected trailing bytes", n),
+            MessageError::NestingTooDeep => write!(f, "Tunnel hops are nested too deeply"),
+            MessageError::UnknownFlags(flags) => write!(f, "Unknown message flags: {:#04x}", flags),
+            MessageError::PolicyViolation(address) => {
+                write!(f, "The address policy doesn't allow {}", address)
+            }
+            MessageError::ReservedAddressType(t) => {
+                write!(f, "Address type {} is reserved for built-in addresses", t)
+            }
+            MessageError::InvalidAddress(reason) => write!(f, "Invalid address {}", reason),
+            MessageError::NullLocalAddress => write!(f, "The local address is null"),
+            MessageError::InvalidUtf8 => write!(f, "Text isn't valid UTF-8"),
+            MessageError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported wire protocol version: {}", v)
+            }
+            MessageError::UnknownContentType(t) => write!(f, "Unknown content type: {}", t),
+            MessageError::BodyTooLarge { max, got } => write!(
+                f,
+                "The message body is too large. Allowed: {} bytes, got: {}",
+                max, got
+            ),
+            MessageError::TooManyMessages(n) => {
+                write!(f, "The batch declares too many messages: {}", n)
+            }
+            MessageError::InvalidPresenceByte(b) => write!(f, "Invalid presence byte: {}", b),
+            MessageError::NotBodyOnly => write!(f, "The message isn't body only"),
+            MessageError::UnknownRouteDelta(t) => write!(f, "Unknown route delta type: {}", t),
+            MessageError::MissingNonce => write!(f, "The message has no nonce"),
+            MessageError::ReplayedNonce(n) => write!(f, "Replayed message nonce: {}", n),
+            MessageError::StaleNonce(n) => write!(f, "Message nonce is too old: {}", n),
+            MessageError::MacMismatch => write!(f, "The message authentication code doesn't match"),
+            MessageError::InvalidText(reason) => write!(f, "Invalid message text: {}", reason),
+            MessageError::Io(kind) => write!(f, "I/O error: {:?}", kind),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+impl From<MessageError> for std::io::Error {
+    fn from(err: MessageError) -> Self {
+        let kind = match err {
+            MessageError::Io(kind) => kind,
+            MessageError::BufferTooShort { .. } | MessageError::RouteTruncated { .. } => {
+                std::io::ErrorKind::UnexpectedEof
+            }
+            MessageError::NotImplemented => std::io::ErrorKind::Other,
+            MessageError::EmptyRoute
+            | MessageError::RouteTooLong(_)
+            | MessageError::ValueTooLarge => std::io::ErrorKind::InvalidInput,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+impl From<std::io::Error> for MessageError {
+    fn from(err: std::io::Error) -> Self {
+        match err.get_ref().and_then(|e| e.downcast_ref::<MessageError>()) {
+            Some(inner) => inner.clone(),
+            None => MessageError::Io(err.kind()),
+        }
+    }
+}
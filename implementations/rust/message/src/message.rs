@@ -4,7 +4,40 @@
 // Each message component, and the message overall, implements the "Codec" trait
 // allowing it to be encoded/decoded for transmission over a transport.
 
+pub mod error;
+
+// The crate's own items in `message` are also available from the crate root, so callers
+// can write `ockam_message::Message` rather than `ockam_message::message::Message`
+#[cfg(feature = "interface-resolve")]
+pub use message::OsInterfaceResolver;
+#[cfg(feature = "mac")]
+pub use message::MAC_LEN;
+pub use message::{
+    decode_routes_shared, encode_routes_shared, Address, AddressCodecRegistry, AddressFamily,
+    AddressPolicy, AddressType, Blob, BodyLimits, BodyOnlyMessage, ByPriority, Codec, ContentType,
+    CostModel, DecodeConfig, DecodeStats, FixedU16, Handshake, HostAddressType, InterfaceResolver,
+    LocalAddress, LoopbackOnlyPolicy, Message, MessageCow, MessageError, MessagePool,
+    MessageStreamDecoder, PooledMessage, RemoteHopCostModel, ReplayWindow, Route, RouteBuilder,
+    RouteDelta, RouteSummary, RouteTable, SizeBreakdown, TenantFilter, ValidateOptions, WireFormat,
+    WireProtocolVersion, BODY_ONLY_MARKER, DEFAULT_PRIORITY, EXT_FLAG_FIXED_U16,
+    EXT_FLAG_OPTIONAL_FIELDS, FLAG_BODY_TRUNCATED, FLAG_EXTENDED, FLAG_HINTS, FLAG_PRIORITY,
+    FLAG_REPLY_EXPECTED, FLAG_TTL, FLAG_VARINT_PORTS, HINT_CONTENT_TYPE, MAX_BLOB_LEN,
+    MAX_ROUTE_HOPS, MAX_TUNNEL_DEPTH, MAX_WORKER_NAME_LEN, REPLAY_WINDOW_LEN, WIRE_BYTE_ORDER,
+    WIRE_PROTOCOL_VERSION,
+};
+
+/// The types most code needs to build, encode and decode messages, together with the
+/// `Codec` trait their `encode` and `decode` come from: `use ockam_message::prelude::*;`
+pub mod prelude {
+    pub use crate::message::{
+        Address, AddressType, Codec, LocalAddress, Message, MessageError, Route,
+    };
+}
+
 pub mod message {
+    pub use crate::error::MessageError;
+    use std::borrow::Cow;
+    use std::collections::{HashMap, HashSet};
     use std::convert::{Into, TryFrom};
     use std::error::Error;
     use std::fmt::Formatter;
@@ -13,752 +46,7860 @@ pub mod message {
     use std::ops::Add;
     use std::slice;
     use std::sync::atomic::Ordering::AcqRel;
+    use std::sync::Arc;
 
-    const WIRE_PROTOCOL_VERSION: u8 = 1;
+    /// The wire protocol version written at the front of every encoded message
+    pub const WIRE_PROTOCOL_VERSION: u16 = 1;
 
-    pub trait Codec {
-        type Inner;
+    /// The byte order of the wire format, which is part of the wire contract and doesn't
+    /// depend on the host. Fixed-width integers, such as local addresses and ports, are
+    /// little-endian. Variable-length integers are written least significant group first.
+    pub const WIRE_BYTE_ORDER: &str = "little-endian";
 
-        fn encode(t: &Self::Inner, v: &mut Vec<u8>) -> Result<(), String>;
-        fn decode(s: &[u8]) -> Result<(Self::Inner, &[u8]), String>;
-        fn decode_boxed(s: &[u8]) -> Result<(Box<Self::Inner>, &[u8]), String> {
-            Err("not implemented".to_string())
-        }
-    }
+    /// The deepest a tunnel hop may nest routes inside one another, unless a `DecodeConfig`
+    /// says otherwise
+    pub const MAX_TUNNEL_DEPTH: usize = 8;
 
-    #[derive(Debug)]
-    #[repr(C)]
-    pub struct Message {
-        pub onward_route: Route,
-        pub return_route: Route,
-        pub message_body: Vec<u8>,
-    }
+    /// The most hops a route may have. Both the route-building helpers and the codec enforce
+    /// it, so it matches the 255 a single count byte allowed before counts were varints.
+    pub const MAX_ROUTE_HOPS: usize = 255;
 
-    impl Default for Message {
-        fn default() -> Message {
-            Message {
-                onward_route: Route { addresses: vec![] },
-                return_route: Route { addresses: vec![] },
-                message_body: vec![0],
-            }
-        }
-    }
+    /// The longest name, in bytes, a named local worker may have
+    pub const MAX_WORKER_NAME_LEN: usize = 255;
 
-    impl Codec for Message {
-        type Inner = Message;
-        fn encode(msg: &Message, u: &mut Vec<u8>) -> Result<(), String> {
-            Route::encode(&msg.onward_route, u);
-            Route::encode(&msg.return_route, u);
-            u.extend(&msg.message_body[0..]);
-            Ok(())
-        }
+    /// Options controlling how untrusted input is decoded
+    #[derive(Clone, Debug)]
+    pub struct DecodeConfig {
+        /// How deeply tunnel hops may nest routes before decoding fails with `NestingTooDeep`
+        pub max_depth: usize,
+        /// Codecs for address types beyond the built-in ones, decoded as `Address::Custom`
+        pub registry: Option<Arc<AddressCodecRegistry>>,
+        /// The largest body a message may carry for its content type
+        pub body_limits: BodyLimits,
+    }
 
-        fn decode(u: &[u8]) -> Result<(Message, &[u8]), String> {
-            let mut msg = Message::default();
-            let mut w = u;
-            match Route::decode(w) {
-                Ok((r, u1)) => {
-                    msg.onward_route = r;
-                    w = u1;
-                }
-                Err(s) => {
-                    return Err(s);
-                }
-            }
-            match Route::decode(w) {
-                Ok((r, u1)) => {
-                    msg.return_route = r;
-                    w = u1;
-                }
-                Err(s) => {
-                    return Err(s);
-                }
-            }
-            msg.message_body.append(&mut (w.to_vec()));
-            Ok((msg, w))
-        }
-        fn decode_boxed(u: &[u8]) -> Result<(Box<Message>, &[u8]), String> {
-            let mut msg = Box::new(Message::default());
-            let mut w = u;
-            match Route::decode(w) {
-                Ok((r, u1)) => {
-                    msg.onward_route = r;
-                    w = u1;
-                }
-                Err(s) => {
-                    return Err(s);
-                }
-            }
-            match Route::decode(w) {
-                Ok((r, u1)) => {
-                    msg.return_route = r;
-                    w = u1;
-                }
-                Err(s) => {
-                    return Err(s);
-                }
+    impl Default for DecodeConfig {
+        fn default() -> DecodeConfig {
+            DecodeConfig {
+                max_depth: MAX_TUNNEL_DEPTH,
+                registry: None,
+                body_limits: BodyLimits::default(),
             }
-            msg.message_body.append(&mut (w.to_vec()));
-            Ok((msg, w))
         }
     }
 
-    /* Addresses */
-    #[repr(C)]
-    pub enum AddressType {
-        Local = 0,
-        Tcp = 1,
-        Udp = 2,
-    }
+    /// The hint key a message's `ContentType` is carried under, as a single byte
+    pub const HINT_CONTENT_TYPE: u16 = 1;
 
-    impl Clone for AddressType {
-        fn clone(&self) -> Self {
-            return match self {
-                AddressType::Local => AddressType::Local,
-                AddressType::Tcp => AddressType::Tcp,
-                AddressType::Udp => AddressType::Udp,
-            };
-        }
+    /// What a message body holds. A message without a content type hint is `Raw`.
+    #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+    pub enum ContentType {
+        Raw = 0,
+        Json = 1,
+        Control = 2,
     }
 
-    impl std::fmt::Debug for AddressType {
-        fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
-            let s: String;
-            match self {
-                AddressType::Local => {
-                    s = "Local".to_string();
-                }
-                AddressType::Tcp => {
-                    s = "Tcp".to_string();
-                }
-                AddressType::Udp => {
-                    s = "Udp".to_string();
-                }
+    impl TryFrom<u8> for ContentType {
+        type Error = MessageError;
+        fn try_from(data: u8) -> Result<Self, Self::Error> {
+            match data {
+                0 => Ok(ContentType::Raw),
+                1 => Ok(ContentType::Json),
+                2 => Ok(ContentType::Control),
+                _ => Err(MessageError::UnknownContentType(data)),
             }
-            f.debug_struct("AddressType").field("Type", &s).finish();
-            Ok(())
         }
     }
 
-    impl Copy for AddressType {}
-
-    #[derive(Debug, PartialEq)]
-    #[repr(C)]
-    #[derive(Clone, Copy)]
-    pub struct LocalAddress {
-        pub address: u32,
-    }
-
-    // ToDo: implement Copy, Clone
-    #[repr(C)]
-    #[derive(Clone, Copy, Debug, PartialEq)]
-    pub enum Address {
-        LocalAddress(AddressType, LocalAddress),
-        TcpAddress(AddressType, IpAddr, u16),
-        UdpAddress(AddressType, IpAddr, u16),
+    /// The largest body allowed for each content type. Content types without a limit
+    /// aren't restricted.
+    #[derive(Clone, Debug, Default)]
+    pub struct BodyLimits {
+        limits: HashMap<ContentType, usize>,
     }
 
-    pub enum HostAddressType {
-        Ipv4 = 0,
-        Ipv6 = 1,
-    }
+    impl BodyLimits {
+        pub fn new() -> BodyLimits {
+            BodyLimits::default()
+        }
 
-    impl PartialEq for AddressType {
-        fn eq(&self, other: &Self) -> bool {
-            let t: u8 = *self as u8;
-            let o = *other as u8;
-            return t == o;
+        /// Limit bodies of `content_type` to `max` bytes, replacing any earlier limit
+        pub fn set(&mut self, content_type: ContentType, max: usize) {
+            self.limits.insert(content_type, max);
         }
 
-        fn ne(&self, other: &Self) -> bool {
-            let t = *self as u8;
-            let o = *other as u8;
-            return t != o;
+        /// The limit for `content_type`, if there is one
+        pub fn get(&self, content_type: ContentType) -> Option<usize> {
+            self.limits.get(&content_type).copied()
         }
-    }
 
-    impl TryFrom<u8> for HostAddressType {
-        type Error = String;
-        fn try_from(data: u8) -> Result<Self, Self::Error> {
-            match data {
-                0 => Ok(HostAddressType::Ipv4),
-                1 => Ok(HostAddressType::Ipv6),
-                _ => Err("Unknown host address type".to_string()),
+        // Check a body of `len` bytes against the limit for the content type `msg` is tagged
+        // with, before the body is copied anywhere
+        fn check(&self, msg: &Message, len: usize) -> Result<(), MessageError> {
+            if self.limits.is_empty() {
+                return Ok(());
+            }
+            match self.get(msg.content_type()?) {
+                Some(max) if len > max => Err(MessageError::BodyTooLarge { max, got: len }),
+                _ => Ok(()),
             }
         }
     }
 
-    impl TryFrom<u8> for AddressType {
-        type Error = String;
-        fn try_from(data: u8) -> Result<Self, Self::Error> {
-            match data {
-                0 => Ok(AddressType::Local),
-                1 => Ok(AddressType::Tcp),
-                2 => Ok(AddressType::Udp),
-                _ => Err("Unknown address type".to_string()),
-            }
-        }
+    type CustomEncodeFn = dyn Fn(&[u8], &mut Vec<u8>) -> Result<(), MessageError> + Send + Sync;
+    type CustomDecodeFn = dyn Fn(&[u8]) -> Result<(Vec<u8>, usize), MessageError> + Send + Sync;
+
+    /// Codecs for address types defined outside this crate, keyed by type byte. The encoder
+    /// writes the wire form of an `Address::Custom` payload after its type byte. The decoder
+    /// reads the wire form back, returning the payload and how many bytes it consumed.
+    #[derive(Default)]
+    pub struct AddressCodecRegistry {
+        codecs: HashMap<u8, (Box<CustomEncodeFn>, Box<CustomDecodeFn>)>,
     }
 
-    impl Codec for Address {
-        type Inner = Address;
-        fn encode(a: &Address, v: &mut Vec<u8>) -> Result<(), String> {
-            match a {
-                Address::LocalAddress(mut t, a) => {
-                    v.push(t as u8);
-                    LocalAddress::encode(a, v);
-                }
-                Address::UdpAddress(mut t, ipa, mut port) => {
-                    v.push(t as u8);
-                    IpAddr::encode(ipa, v);
-                    v.append(&mut port.to_le_bytes().to_vec());
-                }
-                Address::TcpAddress(mut t, ipa, mut port) => {
-                    v.push(t as u8);
-                    IpAddr::encode(ipa, v);
-                    v.append(&mut port.to_le_bytes().to_vec());
-                }
-            }
-            Ok(())
+    impl AddressCodecRegistry {
+        pub fn new() -> AddressCodecRegistry {
+            AddressCodecRegistry::default()
         }
-        fn decode(u: &[u8]) -> Result<(Address, &[u8]), String> {
-            match AddressType::try_from(u[0])? {
-                AddressType::Local => {
-                    let (la, v) = LocalAddress::decode(&u[1..])?;
-                    let address = Address::LocalAddress(AddressType::Local, la);
-                    Ok((address, v))
-                }
-                AddressType::Tcp => Err("Not Implemented".to_string()),
-                AddressType::Udp => {
-                    let (ipa, v) = IpAddr::decode(&u[1..])?;
-                    let port = u16::from_le_bytes([v[0], v[1]]);
-                    let address = Address::UdpAddress(AddressType::Udp, ipa, port);
-                    Ok((address, &v[2..]))
-                }
+
+        /// Register the codec for an address type, replacing any registered before. The
+        /// built-in address types can't be overridden.
+        pub fn register<E, D>(
+            &mut self,
+            address_type: u8,
+            encode: E,
+            decode: D,
+        ) -> Result<(), MessageError>
+        where
+            E: Fn(&[u8], &mut Vec<u8>) -> Result<(), MessageError> + Send + Sync + 'static,
+            D: Fn(&[u8]) -> Result<(Vec<u8>, usize), MessageError> + Send + Sync + 'static,
+        {
+            if AddressType::try_from(address_type).is_ok() {
+                return Err(MessageError::ReservedAddressType(address_type));
             }
+            self.codecs
+                .insert(address_type, (Box::new(encode), Box::new(decode)));
+            Ok(())
         }
-    }
 
-    impl Codec for IpAddr {
-        type Inner = IpAddr;
-        fn encode(ip: &IpAddr, v: &mut Vec<u8>) -> Result<(), String> {
-            match ip {
-                std::net::IpAddr::V4(ip4) => {
-                    v.push(HostAddressType::Ipv4 as u8);
-                    v.extend_from_slice(ip4.octets().as_ref());
-                }
-                std::net::IpAddr::V6(ip6) => {
-                    v.push(HostAddressType::Ipv6 as u8);
-                    v.extend_from_slice(ip6.octets().as_ref());
+        fn encode(&self, t: u8, data: &[u8], v: &mut Vec<u8>) -> Result<(), MessageError> {
+            match self.codecs.get(&t) {
+                Some((encode, _)) => {
+                    v.push(t);
+                    encode(data, v)
                 }
+                None => Err(MessageError::UnknownAddressType(t)),
             }
-            Ok(())
         }
-        fn decode(u: &[u8]) -> Result<(IpAddr, &[u8]), String> {
-            match (HostAddressType::try_from(u[0])?, &u[1..]) {
-                (HostAddressType::Ipv4, addr) => {
-                    let ip4 = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
-                    let ip_addr = IpAddr::V4(ip4);
-                    Ok((ip_addr, &u[5..]))
+
+        fn decode<'a>(&self, t: u8, u: &'a [u8]) -> Result<(Address, &'a [u8]), MessageError> {
+            match self.codecs.get(&t) {
+                Some((_, decode)) => {
+                    let (data, n) = decode(u)?;
+                    if n > u.len() {
+                        return Err(MessageError::BufferTooShort {
+                            needed: n,
+                            got: u.len(),
+                        });
+                    }
+                    Ok((Address::Custom(t, data), &u[n..]))
                 }
-                _ => Err("".to_string()),
+                None => Err(MessageError::UnknownAddressType(t)),
             }
         }
     }
 
-    impl Codec for LocalAddress {
-        type Inner = LocalAddress;
-        fn encode(la: &LocalAddress, u: &mut Vec<u8>) -> Result<(), String> {
-            for le_byte in la.address.to_le_bytes().iter() {
-                u.push(*le_byte);
-            }
-            Ok(())
-        }
-        fn decode(u: &[u8]) -> Result<(LocalAddress, &[u8]), String> {
-            Ok((
-                LocalAddress {
-                    address: u32::from_le_bytes([u[0], u[1], u[2], u[3]]),
-                },
-                &u[4..],
-            ))
+    impl std::fmt::Debug for AddressCodecRegistry {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            let mut types: Vec<&u8> = self.codecs.keys().collect();
+            types.sort();
+            f.debug_struct("AddressCodecRegistry")
+                .field("address_types", &types)
+                .finish()
         }
     }
 
-    /* Routes */
-    #[derive(Debug)]
-    #[repr(C)]
-    pub struct Route {
-        pub addresses: Vec<Address>,
-    }
+    pub trait Codec {
+        type Inner;
 
-    impl Clone for Route {
-        fn clone(&self) -> Self {
-            return Route {
-                addresses: self.addresses.clone(),
-            };
+        fn encode(t: &Self::Inner, v: &mut Vec<u8>) -> Result<(), MessageError>;
+        fn decode(s: &[u8]) -> Result<(Self::Inner, &[u8]), MessageError>;
+        fn decode_boxed(s: &[u8]) -> Result<(Box<Self::Inner>, &[u8]), MessageError> {
+            Err(MessageError::NotImplemented)
         }
+    }
 
-        fn clone_from(&mut self, source: &Self) {
-            unimplemented!()
-        }
+    /// Message header flag set when the sender expects a reply
+    pub const FLAG_REPLY_EXPECTED: u8 = 0x01;
+
+    /// Message header flag set when ports are encoded with the variable-length u16 codec
+    pub const FLAG_VARINT_PORTS: u8 = 0x02;
+
+    /// Message header flag set when the body was dropped to fit the message into an MTU
+    pub const FLAG_BODY_TRUNCATED: u8 = 0x08;
+
+    /// Message header flag set when a list of routing hints follows the return route
+    pub const FLAG_HINTS: u8 = 0x04;
+
+    /// Message header flag set when a TTL byte follows the flags
+    pub const FLAG_TTL: u8 = 0x10;
+
+    /// Message header flag set when a priority byte follows the flags and TTL
+    pub const FLAG_PRIORITY: u8 = 0x20;
+
+    /// Message header flag set when a second byte of flags follows the first
+    pub const FLAG_EXTENDED: u8 = 0x80;
+
+    /// Extended header flag set when the u16 values after the flags, ports and hint keys, are
+    /// encoded as two little-endian bytes rather than with the variable-length u16 codec
+    pub const EXT_FLAG_FIXED_U16: u8 = 0x01;
+
+    /// Extended header flag set when the optional fields follow the priority: the replay
+    /// protection nonce, the tenant, then the affinity key. Each is written by `encode_option`, a presence byte followed by
+    /// the value if there is one. The optional fields share this one flag rather than taking a
+    /// flag bit each, so adding one doesn't use up flag bits, and a frame without any of them
+    /// doesn't pay for their presence bytes.
+    pub const EXT_FLAG_OPTIONAL_FIELDS: u8 = 0x02;
+
+    /// The priority of a message nobody has prioritized, halfway between the lowest, 0, and
+    /// the highest. Messages at this priority don't encode it.
+    pub const DEFAULT_PRIORITY: u8 = 128;
+
+    // Flags describing how the frame is laid out rather than the message. These are set
+    // while encoding, from the WireFormat and which optional fields are present, and never
+    // kept in Message::flags.
+    const FORMAT_FLAGS: u8 =
+        FLAG_VARINT_PORTS | FLAG_HINTS | FLAG_TTL | FLAG_PRIORITY | FLAG_EXTENDED;
+
+    const KNOWN_FLAGS: u8 = FLAG_REPLY_EXPECTED | FLAG_BODY_TRUNCATED | FORMAT_FLAGS;
+
+    const KNOWN_EXT_FLAGS: u8 = EXT_FLAG_FIXED_U16 | EXT_FLAG_OPTIONAL_FIELDS;
+
+    /// Selects between alternative wire encodings. The default is the fixed-width layout every
+    /// peer understands; a message records the format it was encoded with in its header flags.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct WireFormat {
+        /// Encode ports with the variable-length u16 codec rather than as two little-endian
+        /// bytes. Ports below 0x80 then take one byte, but ports of 0x8000 and up can't be
+        /// encoded.
+        pub varint_ports: bool,
+        /// Encode every u16 after the header flags, ports and hint keys, as two little-endian
+        /// bytes, for peers in other languages without the variable-length u16 codec. This
+        /// takes precedence over `varint_ports`. The version in front of the flags is still a
+        /// varint, which for the current version is the same single byte.
+        pub fixed_u16: bool,
     }
 
-    impl Codec for Route {
-        type Inner = Route;
-        fn encode(route: &Route, u: &mut Vec<u8>) -> Result<(), String> {
-            if route.addresses.is_empty() {
-                u.push(0 as u8)
+    impl WireFormat {
+        fn flags(&self) -> u8 {
+            if self.varint_port_encoding() {
+                FLAG_VARINT_PORTS
             } else {
-                u.push(route.addresses.len() as u8);
-                for i in 0..route.addresses.len() {
-                    Address::encode(&route.addresses[i], u);
-                }
-            }
-            Ok(())
-        }
-        fn decode(encoded: &[u8]) -> Result<(Route, &[u8]), String> {
-            let mut route = Route { addresses: vec![] };
-            let mut next_address = &encoded[1..];
-            if 0 < encoded[0] {
-                for i in 0..encoded[0] as usize {
-                    match Address::decode(next_address) {
-                        Ok((a, x)) => {
-                            route.addresses.push(a);
-                            next_address = x;
-                        }
-                        Err(s) => {}
-                    }
-                }
+                0
             }
-            Ok((route, next_address))
         }
-    }
-
-    // ToDo: Implement PartialEq, Eq, Copy, Clone
-
-    // u16's are encoded as variable-length.
-    // - If the value is < 0x80, it is encoded as-is, in one byte
-    // - If the value is <= 0x80, the highest-order of the low-order byte is moved to the
-    //   lowest-order bit in the high-order byte, and the high-order byte is shifted left by one to
-    //   make room.
-    impl Codec for u16 {
-        type Inner = u16;
-        fn encode(ul2: &u16, u: &mut Vec<u8>) -> Result<(), String> {
-            if ul2 >= &mut 0xC000 {
-                return Err("Maximum value exceeded".to_string());
-            }
-            let mut bytes = ul2.to_le_bytes();
 
-            if ul2 < &mut 0x80 {
-                u.push(bytes[0])
+        fn ext_flags(&self) -> u8 {
+            if self.fixed_u16 {
+                EXT_FLAG_FIXED_U16
             } else {
-                bytes[1] <<= 0x01;
-                if 0 != (bytes[0] & 0x80) {
-                    bytes[1] |= 0x01;
-                }
-                bytes[0] |= 0x80;
-                u.push(bytes[0]);
-                u.push(bytes[1])
+                0
             }
-            Ok(())
         }
 
-        fn decode(u: &[u8]) -> Result<(u16, &[u8]), String> {
-            let mut bytes = [0, 0];
-            let mut i = 1;
-
-            bytes[0] = u[0] & 0x7f;
-            if (u[0] & 0x80) == 0x80 as u8 {
-                bytes[0] += (u[1] & 0x01) << 7;
-                bytes[1] = u[1] >> 1;
-                i = 2;
+        fn from_flags(flags: u8, ext_flags: u8) -> WireFormat {
+            WireFormat {
+                varint_ports: flags & FLAG_VARINT_PORTS != 0,
+                fixed_u16: ext_flags & EXT_FLAG_FIXED_U16 != 0,
             }
-            let ul2 = ((bytes[1] as u16) << 8) + bytes[0] as u16;
+        }
 
-            Ok((ul2, &u[i..]))
+        fn varint_port_encoding(&self) -> bool {
+            self.varint_ports && !self.fixed_u16
         }
     }
 
     #[derive(Debug)]
     #[repr(C)]
-    pub struct WireProtocolVersion {
-        pub v: u16,
-    }
-
-    impl Default for WireProtocolVersion {
-        fn default() -> WireProtocolVersion {
-            WireProtocolVersion { v: 1 }
-        }
+    pub struct Message {
+        pub onward_route: Route,
+        pub return_route: Route,
+        pub message_body: Vec<u8>,
+        /// Header flags, encoded in a single byte ahead of the routes
+        pub flags: u8,
+        /// Routing hints that aren't addresses, such as a preferred transport, as pairs of
+        /// key and value. They are only encoded when there are some.
+        pub hints: Vec<(u16, Vec<u8>)>,
+        /// How many more hops relays may forward the message. It is only encoded when set,
+        /// and isn't covered by `signable_ranges` since relays change it.
+        pub ttl: Option<u8>,
+        /// How urgently the message should be processed, from 0, the lowest. It is only
+        /// encoded when it isn't `DEFAULT_PRIORITY`.
+        pub priority: u8,
+        /// The tenant the message belongs to, for relays that keep tenants apart. It is an
+        /// optional field, written as a little-endian u32 when set.
+        pub tenant: Option<u32>,
+        /// A number the sender never reuses within a session, so a `ReplayWindow` can spot
+        /// replayed messages. It is an optional field, written as a u64 varint when set.
+        pub nonce: Option<u64>,
+        /// The key a load-balanced relay hashes to pin the message's flow to one backend. It
+        /// is an optional field, written as a u64 varint when set.
+        pub affinity: Option<u64>,
+        /// The local transport address the message arrived on, recorded by the transport
+        /// that received it so replies leave the same way. It isn't part of the wire format.
+        pub ingress: Option<Address>,
     }
 
-    // std::io::Read & std::io::Write trait implementation
-    impl std::io::Read for Message {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
-            if buf.len() < self.message_body.len() {
-                return Err(std::io::Error::new(ErrorKind::Other, "buffer too small"));
-            }
-            for i in 0..self.message_body.len() {
-                buf[i] = self.message_body[i];
+    impl Default for Message {
+        fn default() -> Message {
+            Message {
+                onward_route: Route { addresses: vec![] },
+                return_route: Route { addresses: vec![] },
+                message_body: vec![0],
+                flags: 0,
+                hints: vec![],
+                ttl: None,
+                priority: DEFAULT_PRIORITY,
+                tenant: None,
+                nonce: None,
+                affinity: None,
+                ingress: None,
             }
-            Ok(self.message_body.len())
         }
     }
-    impl std::io::Write for Message {
-        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-            if !self.message_body.is_empty() {
-                return Err(std::io::Error::new(ErrorKind::Other, "no message body"));
+
+    /// Counts of decode outcomes, for spotting misbehaving peers
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub struct DecodeStats {
+        /// Frames that decoded successfully
+        pub messages_decoded: u64,
+        /// Frames that failed, keyed by the name of the `MessageError` variant
+        pub errors_by_kind: HashMap<&'static str, u64>,
+    }
+
+    /// Optional checks `Message::validate` applies on top of the hop limits
+    #[derive(Clone, Debug, Default)]
+    pub struct ValidateOptions {
+        /// Reject a message whose onward route ends at the `NULL` local address
+        pub reject_null_endpoint: bool,
+    }
+
+    /// Counts describing a message's routes, for metrics
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct RouteSummary {
+        pub onward_hops: usize,
+        pub return_hops: usize,
+        pub body_len: usize,
+        /// Hops across both routes that leave the node, including tunnel hops
+        pub remote_hops: usize,
+        /// Hops across both routes addressed to local workers
+        pub local_hops: usize,
+    }
+
+    /// How many bytes each section of an encoded message takes, for finding out what makes a
+    /// frame larger than expected
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    pub struct SizeBreakdown {
+        pub version: usize,
+        pub flags: usize,
+        /// Zero when the message has no TTL
+        pub ttl: usize,
+        /// Zero when the message has the default priority
+        pub priority: usize,
+        /// Zero when the message has none of the fields `EXT_FLAG_OPTIONAL_FIELDS` marks
+        pub optional_fields: usize,
+        pub onward_route: usize,
+        pub return_route: usize,
+        /// Zero when the message has no hints
+        pub hints: usize,
+        pub body: usize,
+        /// The sum of the sections, which is what `encode` writes
+        pub total: usize,
+    }
+
+    impl Message {
+        /// Summarize the message's routes in a single pass over their hops
+        pub fn route_summary(&self) -> RouteSummary {
+            let mut summary = RouteSummary {
+                onward_hops: self.onward_route.addresses.len(),
+                return_hops: self.return_route.addresses.len(),
+                body_len: self.message_body.len(),
+                ..Default::default()
+            };
+            let hops = self
+                .onward_route
+                .addresses
+                .iter()
+                .chain(self.return_route.addresses.iter());
+            for a in hops {
+                match a.unaliased() {
+                    Address::LocalAddress(_, _) | Address::NamedLocal(_, _) => {
+                        summary.local_hops += 1
+                    }
+                    _ => summary.remote_hops += 1,
+                }
             }
-            for i in 0..buf.len() {
-                self.message_body.push(buf[i]);
+            summary
+        }
+
+        /// Whether the message carries nothing: no hops on either route, no hints and an
+        /// empty body. The header flags, TTL, priority, tenant and nonce aren't considered. An
+        /// empty message with the default header encodes to just the version, the flags and
+        /// two zero route counts.
+        pub fn is_empty(&self) -> bool {
+            self.onward_route.addresses.is_empty()
+                && self.return_route.addresses.is_empty()
+                && self.hints.is_empty()
+                && self.message_body.is_empty()
+        }
+
+        /// How many bytes `encode` writes for this message
+        pub fn encoded_len(&self) -> usize {
+            self.size_breakdown().total
+        }
+
+        /// A quick bound on how many bytes encoding this message takes in any wire format, for
+        /// picking a pooled buffer. Fixed-size addresses are counted at the size of the
+        /// largest, an IPv6 address and port, and every optional header field as present.
+        pub fn encoded_len_upper_bound(&self) -> usize {
+            let route_bound = |route: &Route| {
+                MAX_VARINT_U64_LEN
+                    + route
+                        .addresses
+                        .iter()
+                        .map(|a| match a {
+                            Address::LocalAddress(..)
+                            | Address::UdpAddress(..)
+                            | Address::TcpAddress(..) => MAX_FIXED_ADDRESS_LEN,
+                            a => a.encoded_len(),
+                        })
+                        .sum::<usize>()
+            };
+            let hints_bound = MAX_VARINT_U64_LEN
+                + self
+                    .hints
+                    .iter()
+                    .map(|(_, value)| 2 + blob_encoded_len(value.len()))
+                    .sum::<usize>();
+            MAX_HEADER_LEN
+                + route_bound(&self.onward_route)
+                + route_bound(&self.return_route)
+                + hints_bound
+                + self.message_body.len()
+        }
+
+        /// The bytes of an encoded frame a signature should cover, as the ranges before and
+        /// after its TTL byte. Only the TTL is left out, as relays decrement it; the flags are
+        /// covered since they decide how the rest of the frame is read. Without a TTL the
+        /// second range is empty.
+        pub fn signable_ranges(u: &[u8]) -> Result<[std::ops::Range<usize>; 2], MessageError> {
+            let w = decode_version(u)?;
+            let (header, _) = read_flags(w)?;
+            if header.ttl.is_none() {
+                return Ok([0..u.len(), u.len()..u.len()]);
             }
-            Ok(self.message_body.len())
+            let flags_len = if header.flags & FLAG_EXTENDED != 0 {
+                2
+            } else {
+                1
+            };
+            let ttl_at = u.len() - w.len() + flags_len;
+            Ok([0..ttl_at, ttl_at + 1..u.len()])
         }
-        fn flush(&mut self) -> Result<(), std::io::Error> {
-            return Ok(());
+
+        /// How many bytes `encode` writes for each section of this message
+        pub fn size_breakdown(&self) -> SizeBreakdown {
+            let mut sizes = SizeBreakdown {
+                version: varint_u16_len(WIRE_PROTOCOL_VERSION),
+                flags: if self.has_optional_fields() { 2 } else { 1 },
+                ttl: self.ttl.map_or(0, |_| 1),
+                priority: if self.priority == DEFAULT_PRIORITY {
+                    0
+                } else {
+                    1
+                },
+                optional_fields: self.optional_fields_len(),
+                onward_route: self.onward_route.encoded_len(),
+                return_route: self.return_route.encoded_len(),
+                hints: hints_encoded_len(&self.hints),
+                body: self.message_body.len(),
+                total: 0,
+            };
+            sizes.total = sizes.version
+                + sizes.flags
+                + sizes.ttl
+                + sizes.priority
+                + sizes.optional_fields
+                + sizes.onward_route
+                + sizes.return_route
+                + sizes.hints
+                + sizes.body;
+            sizes
         }
-    }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::message::*;
-    use std::net::{IpAddr, Ipv4Addr};
+        // Whether the message has any of the fields `EXT_FLAG_OPTIONAL_FIELDS` marks
+        fn has_optional_fields(&self) -> bool {
+            self.nonce.is_some() || self.tenant.is_some() || self.affinity.is_some()
+        }
 
-    #[test]
-    fn local_address_codec() {
-        let mut local_in = LocalAddress {
-            address: 0x00010203,
-        };
-        let mut v: Vec<u8> = vec![];
-        LocalAddress::encode(&mut local_in, &mut v);
-        assert_eq!(v, [3, 2, 1, 0]);
-        match LocalAddress::decode(&v) {
-            Ok((local_out, w)) => assert_eq!(
-                local_out,
-                LocalAddress {
-                    address: 0x00010203
-                }
-            ),
-            Err(s) => {
-                println!("{:?}", s);
+        // How many bytes the optional fields take, with their presence bytes
+        fn optional_fields_len(&self) -> usize {
+            if !self.has_optional_fields() {
+                return 0;
             }
+            1 + self.nonce.map_or(0, varint_u64_len)
+                + 1
+                + self.tenant.map_or(0, |_| 4)
+                + 1
+                + self.affinity.map_or(0, varint_u64_len)
         }
-    }
 
-    #[test]
-    fn ip4_address_codec() {
-        let mut v: Vec<u8> = vec![];
-        let mut ip4a: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        IpAddr::encode(&mut ip4a, &mut v);
-        assert_eq!(v, vec![0, 127, 0, 0, 1]);
-        let mut v: Vec<u8> = vec![0, 127, 0, 0, 1];
-        match IpAddr::decode(&v) {
-            Ok((ip4a, w)) => {
-                assert_eq!(ip4a, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        /// Describe the first field in which the messages differ, such as
+        /// "onward_route[1].port: 100 != 200", or None if they are equal
+        pub fn diff(&self, other: &Message) -> Option<String> {
+            diff_route("onward_route", &self.onward_route, &other.onward_route)
+                .or_else(|| diff_route("return_route", &self.return_route, &other.return_route))
+                .or_else(|| {
+                    if self.flags != other.flags {
+                        return Some(format!(
+                            "flags: {:#04x} != {:#04x}",
+                            self.flags, other.flags
+                        ));
+                    }
+                    if self.ttl != other.ttl {
+                        return Some(format!("ttl: {:?} != {:?}", self.ttl, other.ttl));
+                    }
+                    if self.priority != other.priority {
+                        return Some(format!("priority: {} != {}", self.priority, other.priority));
+                    }
+                    if self.tenant != other.tenant {
+                        return Some(format!("tenant: {:?} != {:?}", self.tenant, other.tenant));
+                    }
+                    if self.nonce != other.nonce {
+                        return Some(format!("nonce: {:?} != {:?}", self.nonce, other.nonce));
+                    }
+                    if self.affinity != other.affinity {
+                        return Some(format!(
+                            "affinity: {:?} != {:?}",
+                            self.affinity, other.affinity
+                        ));
+                    }
+                    if self.ingress != other.ingress {
+                        return Some(format!(
+                            "ingress: {:?} != {:?}",
+                            self.ingress.as_ref().map(|a| a.to_string()),
+                            other.ingress.as_ref().map(|a| a.to_string())
+                        ));
+                    }
+                    if self.hints != other.hints {
+                        return Some(format!("hints: {:?} != {:?}", self.hints, other.hints));
+                    }
+                    diff_bytes("message_body", &self.message_body, &other.message_body)
+                })
+        }
+
+        /// The round trip the message and its reply take: the onward hops followed by the
+        /// return hops in reverse
+        pub fn full_path(&self) -> Route {
+            let addresses = self
+                .onward_route
+                .addresses
+                .iter()
+                .chain(self.return_route.addresses.iter().rev())
+                .cloned()
+                .collect();
+            Route { addresses }
+        }
+
+        /// Encode the message and write it to `w`
+        pub fn encode_into<W: std::io::Write>(&self, w: &mut W) -> Result<(), MessageError> {
+            let mut u = Vec::with_capacity(self.encoded_len());
+            Message::encode(self, &mut u)?;
+            w.write_all(&u)?;
+            Ok(())
+        }
+
+        /// Encode the message and write it to an async writer, like `encode_into`
+        #[cfg(feature = "tokio")]
+        pub async fn encode_async<W: tokio::io::AsyncWrite + Unpin>(
+            &self,
+            w: &mut W,
+        ) -> Result<(), MessageError> {
+            use tokio::io::AsyncWriteExt;
+            let mut u = Vec::with_capacity(self.encoded_len());
+            Message::encode(self, &mut u)?;
+            w.write_all(&u).await?;
+            Ok(())
+        }
+
+        /// Encode a message using an alternative wire format, which is recorded in the header
+        /// flags so `decode` needs no help to read it back
+        pub fn encode_with_format(
+            msg: &Message,
+            u: &mut Vec<u8>,
+            format: WireFormat,
+        ) -> Result<(), MessageError> {
+            Message::encode_frame(msg, u, format, None)
+        }
+
+        /// Encode a message whose routes hold custom addresses, using the codecs in `registry`
+        pub fn encode_with_registry(
+            msg: &Message,
+            u: &mut Vec<u8>,
+            registry: &AddressCodecRegistry,
+        ) -> Result<(), MessageError> {
+            Message::encode_frame(msg, u, WireFormat::default(), Some(registry))
+        }
+
+        /// Decode a message, handing address types the crate doesn't know to `registry`
+        pub fn decode_with_registry<'a>(
+            u: &'a [u8],
+            registry: &Arc<AddressCodecRegistry>,
+        ) -> Result<(Message, &'a [u8]), MessageError> {
+            let config = DecodeConfig {
+                registry: Some(Arc::clone(registry)),
+                ..Default::default()
+            };
+            Message::decode_with_config(u, &config)
+        }
+
+        fn encode_frame(
+            msg: &Message,
+            u: &mut Vec<u8>,
+            format: WireFormat,
+            registry: Option<&AddressCodecRegistry>,
+        ) -> Result<(), MessageError> {
+            // Don't leave a partially encoded message behind on failure
+            let start = u.len();
+            u.reserve(msg.encoded_len());
+            u16::encode(&WIRE_PROTOCOL_VERSION, u)?;
+            let mut flags = (msg.flags & !FORMAT_FLAGS) | format.flags();
+            if !msg.hints.is_empty() {
+                flags |= FLAG_HINTS;
+            }
+            if msg.ttl.is_some() {
+                flags |= FLAG_TTL;
+            }
+            if msg.priority != DEFAULT_PRIORITY {
+                flags |= FLAG_PRIORITY;
+            }
+            let mut ext_flags = format.ext_flags();
+            if msg.has_optional_fields() {
+                ext_flags |= EXT_FLAG_OPTIONAL_FIELDS;
+            }
+            if ext_flags != 0 {
+                flags |= FLAG_EXTENDED;
             }
-            Err(s) => {
-                println!("{}", s);
+            u.push(flags);
+            if ext_flags != 0 {
+                u.push(ext_flags);
             }
+            if let Some(ttl) = msg.ttl {
+                u.push(ttl);
+            }
+            if msg.priority != DEFAULT_PRIORITY {
+                u.push(msg.priority);
+            }
+            if msg.has_optional_fields() {
+                encode_option::<u64>(&msg.nonce, u)?;
+                encode_option::<u32>(&msg.tenant, u)?;
+                encode_option::<u64>(&msg.affinity, u)?;
+            }
+            let routes = Route::encode_nested(&msg.onward_route, u, format, registry, 0)
+                .and_then(|()| Route::encode_nested(&msg.return_route, u, format, registry, 0))
+                .and_then(|()| encode_hints(&msg.hints, u, format));
+            if let Err(e) = routes {
+                u.truncate(start);
+                return Err(e);
+            }
+            u.extend(&msg.message_body[0..]);
+            Ok(())
         }
-    }
 
-    #[test]
-    fn address_codec() {
-        let mut address = Address::UdpAddress(
-            AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            0x8080,
-        );
-        let mut v: Vec<u8> = vec![];
-        Address::encode(&mut address, &mut v);
-        assert_eq!(v, vec![2, 0, 127, 0, 0, 1, 0x80, 0x80]);
-        let mut v = vec![2, 0, 127, 0, 0, 1, 0x80, 0x80];
-        match Address::decode(&mut v) {
-            Ok((address, w)) => {
-                assert_eq!(
-                    address,
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                        0x8080
-                    )
-                );
+        /// Decode a message, applying the limits in `config`
+        pub fn decode_with_config<'a>(
+            u: &'a [u8],
+            config: &DecodeConfig,
+        ) -> Result<(Message, &'a [u8]), MessageError> {
+            let mut msg = Message::default();
+            let w = msg.decode_header_into(u, config)?;
+            msg.message_body = w.to_vec();
+            Ok((msg, w))
+        }
+
+        /// Decode just the return route of a frame, stepping over the onward route without
+        /// decoding it, and return it with the body. This suits nodes handling replies, which
+        /// only need the way back.
+        pub fn decode_return_route(u: &[u8]) -> Result<(Route, &[u8]), MessageError> {
+            let (header, w) = read_flags(decode_version(u)?)?;
+            let format = header.format();
+            let w = skip_route(w, format, None)?;
+            let mut return_route = Route { addresses: vec![] };
+            let w = return_route.decode_addresses(w, &DecodeConfig::default(), format, 0)?;
+            if header.flags & FLAG_HINTS == 0 {
+                return Ok((return_route, w));
             }
-            Err(s) => {
-                println!("{}", s);
+            Ok((return_route, skip_hints(w, format)?))
+        }
+
+        /// Decode a message without copying its body, which borrows from `u` instead. This
+        /// suits callers that only look at a message while they hold the frame.
+        pub fn decode_cow(u: &[u8]) -> Result<MessageCow<'_>, MessageError> {
+            let mut header = Message {
+                message_body: vec![],
+                ..Default::default()
+            };
+            let body = header.decode_header_into(u, &DecodeConfig::default())?;
+            Ok(MessageCow {
+                header,
+                body: Cow::Borrowed(body),
+            })
+        }
+
+        /// Decode a message, counting the outcome in `stats`
+        pub fn decode_counting<'a>(
+            u: &'a [u8],
+            stats: &mut DecodeStats,
+        ) -> Result<(Message, &'a [u8]), MessageError> {
+            let result = Message::decode(u);
+            match &result {
+                Ok(_) => stats.messages_decoded += 1,
+                Err(e) => *stats.errors_by_kind.entry(e.name()).or_insert(0) += 1,
             }
+            result
         }
-        let mut address = Address::LocalAddress(
-            AddressType::Local,
-            LocalAddress {
-                address: 0x00010203,
-            },
-        );
-        let mut v: Vec<u8> = vec![];
-        Address::encode(&mut address, &mut v);
-        assert_eq!(v, vec![0, 3, 2, 1, 0]);
-        let mut v = vec![0, 3, 2, 1, 0];
-        match Address::decode(&mut v) {
-            Ok((address, w)) => {
-                assert_eq!(
-                    address,
-                    Address::LocalAddress(
-                        AddressType::Local,
-                        LocalAddress {
-                            address: 0x00010203
-                        }
-                    )
-                );
+
+        /// Encode several messages into one buffer, as a count followed by each message with
+        /// its length ahead of it, since a message's body otherwise runs to the end of the
+        /// input. Nothing is left behind in `u` if any message fails to encode.
+        pub fn encode_batch(msgs: &[Message], u: &mut Vec<u8>) -> Result<(), MessageError> {
+            let start = u.len();
+            let result = msgs.iter().try_fold(vec![], |mut frames, msg| {
+                Message::encode_length_prefixed(msg, &mut frames)?;
+                Ok(frames)
+            });
+            match result {
+                Ok(frames) => {
+                    u64::encode(&(msgs.len() as u64), u)?;
+                    u.extend_from_slice(&frames);
+                    Ok(())
+                }
+                Err(e) => {
+                    u.truncate(start);
+                    Err(e)
+                }
+            }
+        }
+
+        /// Encode a message with its length ahead of it as a variable-length u64, as frames are
+        /// written to a stream read by `MessageStreamDecoder`
+        pub fn encode_length_prefixed(msg: &Message, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            let mut frame = Vec::with_capacity(msg.encoded_len());
+            Message::encode(msg, &mut frame)?;
+            encode_blob(&frame, u)
+        }
+
+        /// Decode messages written by `encode_batch`. A batch declaring more than
+        /// `max_messages` is rejected with `TooManyMessages` before anything is allocated for
+        /// it, so a crafted count can't force a huge allocation.
+        pub fn decode_batch(
+            u: &[u8],
+            max_messages: usize,
+        ) -> Result<(Vec<Message>, &[u8]), MessageError> {
+            Message::decode_batch_with_config(u, max_messages, &DecodeConfig::default())
+        }
+
+        /// Decode messages written by `encode_batch`, applying the limits in `config` to each
+        pub fn decode_batch_with_config<'a>(
+            u: &'a [u8],
+            max_messages: usize,
+            config: &DecodeConfig,
+        ) -> Result<(Vec<Message>, &'a [u8]), MessageError> {
+            let (count, mut w) = u64::decode(u)?;
+            if count > max_messages as u64 {
+                return Err(MessageError::TooManyMessages(count));
             }
-            Err(s) => {
-                println!("{}", s);
+            let mut msgs = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let (frame, x) = decode_blob(w, usize::MAX)?;
+                msgs.push(Message::decode_with_config(frame, config)?.0);
+                w = x;
             }
+            Ok((msgs, w))
         }
-    }
 
-    #[test]
-    fn route_codec() {
-        let mut route: Route = Route { addresses: vec![] };
-        route.addresses.push(Address::UdpAddress(
-            AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            0x8080,
-        ));
-        route.addresses.push(Address::UdpAddress(
-            AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
-            0x7070,
-        ));
-        route.addresses.push(Address::LocalAddress(
-            AddressType::Local,
-            LocalAddress {
-                address: 0x00010203,
-            },
-        ));
-        let mut v: Vec<u8> = vec![];
-        Route::encode(&mut route, &mut v);
-        assert_eq!(
-            v,
-            vec![3, 2, 0, 127, 0, 0, 1, 0x80, 0x80, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2, 1, 0]
-        );
-        match Route::decode(&v) {
-            Ok((r, u)) => {
-                assert_eq!(r.addresses.len(), 3);
-                assert_eq!(
-                    r.addresses[0],
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                        0x8080
-                    )
-                );
-                assert_eq!(
-                    r.addresses[1],
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
-                        0x7070
-                    )
-                );
-                assert_eq!(
-                    r.addresses[2],
-                    Address::LocalAddress(
-                        AddressType::Local,
-                        LocalAddress {
-                            address: 0x00010203
-                        }
-                    )
-                );
-                assert_eq!(v.len(), 22);
+        /// Decode only the routes of a frame, returning them with the undecoded body. The body
+        /// is borrowed from `u` rather than copied, for nodes that forward it untouched.
+        pub fn decode_header(u: &[u8]) -> Result<(Route, Route, &[u8]), MessageError> {
+            let mut msg = Message::default();
+            let body = msg.decode_header_into(u, &DecodeConfig::default())?;
+            Ok((msg.onward_route, msg.return_route, body))
+        }
+
+        /// Decode the routes of a frame and append its body to `body_out`, so a caller reusing
+        /// one buffer across messages doesn't allocate a body for each. Nothing is appended
+        /// if the routes can't be decoded.
+        pub fn decode_body_into(
+            u: &[u8],
+            body_out: &mut Vec<u8>,
+        ) -> Result<(Route, Route), MessageError> {
+            let (onward_route, return_route, body) = Message::decode_header(u)?;
+            body_out.extend_from_slice(body);
+            Ok((onward_route, return_route))
+        }
+
+        /// Decode a message, also returning the wire protocol version the frame was written in
+        pub fn decode_versioned(u: &[u8]) -> Result<(Message, u16), MessageError> {
+            let (version, w) = u16::decode(u)?;
+            Ok((Message::decode_with_version(w, version)?, version))
+        }
+
+        /// Decode a frame that has no version in front of it, as used once the peers have agreed
+        /// on a version out of band, for example with a `Handshake`
+        pub fn decode_with_version(u: &[u8], version: u16) -> Result<Message, MessageError> {
+            check_version(version)?;
+            let mut msg = Message::default();
+            let w = msg.decode_headerless(u, &DecodeConfig::default())?;
+            msg.message_body = w.to_vec();
+            Ok(msg)
+        }
+
+        /// Decode the first onward hop of a frame for forwarding, returning it with the rest
+        /// of the frame after it, undecoded. Only an unreadable first hop is an error; the
+        /// later hops, the return route, the hints and the body aren't looked at.
+        pub fn decode_onward_first(u: &[u8]) -> Result<(Address, &[u8]), MessageError> {
+            let (header, w) = read_flags(decode_version(u)?)?;
+            let (count, w) = match u64::decode(w)? {
+                (0, _) => return Err(MessageError::EmptyRoute),
+                (count, w) => (count, w),
+            };
+            check_route_count(count, w, None)?;
+            Address::decode_nested(w, &DecodeConfig::default(), header.format(), 0)
+        }
+
+        // Decode everything ahead of the body into this message, reusing its route
+        // allocations, and return the body
+        fn decode_header_into<'a>(
+            &mut self,
+            u: &'a [u8],
+            config: &DecodeConfig,
+        ) -> Result<&'a [u8], MessageError> {
+            self.decode_headerless(decode_version(u)?, config)
+        }
+
+        // Decode everything between the version and the body, checking the body against the
+        // limits in `config` without copying it
+        fn decode_headerless<'a>(
+            &mut self,
+            u: &'a [u8],
+            config: &DecodeConfig,
+        ) -> Result<&'a [u8], MessageError> {
+            let (header, w) = self.decode_flags(u)?;
+            let format = header.format();
+            self.onward_route.addresses.clear();
+            self.return_route.addresses.clear();
+            self.hints.clear();
+            let w = self.onward_route.decode_addresses(w, config, format, 0)?;
+            let w = self.return_route.decode_addresses(w, config, format, 0)?;
+            let w = match header.flags & FLAG_HINTS {
+                0 => w,
+                _ => decode_hints_into(&mut self.hints, w, format)?,
+            };
+            config.body_limits.check(self, w.len())?;
+            Ok(w)
+        }
+
+        // Decode the header flags and the optional fields after them, keeping the message's
+        // own flags and returning the header for laying out the rest of the frame
+        fn decode_flags<'a>(
+            &mut self,
+            u: &'a [u8],
+        ) -> Result<(FlagsHeader, &'a [u8]), MessageError> {
+            let (header, w) = read_flags(u)?;
+            self.flags = header.flags & !FORMAT_FLAGS;
+            self.ttl = header.ttl;
+            self.priority = header.priority;
+            self.tenant = header.tenant;
+            self.nonce = header.nonce;
+            self.affinity = header.affinity;
+            Ok((header, w))
+        }
+
+        /// Read how many hops a frame's onward route has without decoding any of them. Fails
+        /// with `ValueTooLarge` if the count doesn't fit in a u8.
+        pub fn peek_onward_hop_count(u: &[u8]) -> Result<u8, MessageError> {
+            let (_, w) = read_flags(decode_version(u)?)?;
+            let (count, _) = u64::decode(w)?;
+            u8::try_from(count).map_err(|_| MessageError::ValueTooLarge)
+        }
+
+        /// Decode a message into this one, reusing its route and body allocations rather than
+        /// allocating new ones. Returns the number of bytes consumed. On error the message is
+        /// left partially decoded.
+        pub fn decode_into(&mut self, u: &[u8]) -> Result<usize, MessageError> {
+            self.decode_into_with_config(u, &DecodeConfig::default())
+        }
+
+        /// Decode a message into this one as `decode_into` does, applying the limits in
+        /// `config`
+        pub fn decode_into_with_config(
+            &mut self,
+            u: &[u8],
+            config: &DecodeConfig,
+        ) -> Result<usize, MessageError> {
+            let w = self.decode_header_into(u, config)?;
+            self.message_body.clear();
+            self.message_body.extend_from_slice(w);
+            Ok(u.len())
+        }
+
+        /// Check the message before sending it: neither route may have more than
+        /// `MAX_ROUTE_HOPS` hops, plus whatever `options` asks for
+        pub fn validate(&self, options: &ValidateOptions) -> Result<(), MessageError> {
+            for route in [&self.onward_route, &self.return_route].iter() {
+                if route.addresses.len() > MAX_ROUTE_HOPS {
+                    return Err(MessageError::RouteTooLong(route.addresses.len()));
+                }
             }
-            Err(s) => {
-                panic!();
+            if options.reject_null_endpoint {
+                if let Some(Address::LocalAddress(_, la)) =
+                    self.onward_route.addresses.last().map(|a| a.unaliased())
+                {
+                    if la.is_null() {
+                        return Err(MessageError::NullLocalAddress);
+                    }
+                }
             }
+            Ok(())
         }
-    }
 
-    #[test]
-    fn u16_codec() {
-        let mut u: Vec<u8> = vec![];
-        let mut n: u16 = 0x7f;
-        u16::encode(&mut n, &mut u);
-        assert_eq!(u.len(), 1);
-        assert_eq!(u[0], 0x7f);
-        match u16::decode(&u) {
-            Ok((m, v)) => {
-                assert_eq!(u[0], 0x7f);
-                assert_eq!(v.len(), 0);
+        /// Check that each hop of the onward route can be reached from the one before it,
+        /// starting from `local`. A route that, say, dials a loopback hop from a global one is
+        /// most likely misconfigured.
+        pub fn validate_routes_reachable(&self, local: &Address) -> Result<(), MessageError> {
+            let mut from = local;
+            for (i, hop) in self.onward_route.addresses.iter().enumerate() {
+                if !hop.is_reachable_from(from) {
+                    return Err(MessageError::InvalidAddress(format!(
+                        "onward_route[{}]: {} isn't reachable from {}",
+                        i, hop, from
+                    )));
+                }
+                from = hop;
             }
-            Err(s) => panic!(),
+            Ok(())
         }
 
-        let mut too_big: u16 = 0xC000;
-        let mut u: Vec<u8> = vec![];
-        match u16::encode(&mut too_big, &mut u) {
-            Ok(()) => panic!(),
-            Err(s) => {}
+        /// Whether the return route is exactly the onward route reversed, as request-response
+        /// setups usually expect. This is a diagnostic only; nothing enforces it.
+        pub fn return_matches_reversed_onward(&self) -> bool {
+            self.return_route
+                .addresses
+                .iter()
+                .eq(self.onward_route.addresses.iter().rev())
         }
 
-        let mut n = 0x80;
-        let mut u: Vec<u8> = vec![];
-        u16::encode(&mut n, &mut u);
-        assert_eq!(u.len(), 2);
-        assert_eq!(u[0], 0x80);
-        assert_eq!(u[1], 0x01);
-        match u16::decode(&u[0..]) {
-            Ok((m, v)) => {
-                assert_eq!(m, 0x80);
-                assert_eq!(v.len(), 0);
+        /// Build a reply carrying `body`, sent along `to_reply_route`. The reply's own return
+        /// route starts empty, for the relays on the way back to fill in, and it stays in
+        /// this message's tenant. Nothing else is carried over.
+        pub fn reply(&self, body: Vec<u8>) -> Message {
+            Message {
+                onward_route: self.to_reply_route(),
+                return_route: Route { addresses: vec![] },
+                message_body: body,
+                tenant: self.tenant,
+                ..Default::default()
             }
-            Err(e) => panic!(),
         }
 
-        let mut n = 0x1300;
-        let mut u: Vec<u8> = vec![];
-        u16::encode(&mut n, &mut u);
-        assert_eq!(u.len(), 2);
-        assert_eq!(u[1], 0x13 << 1);
-        assert_eq!(u[0], 0x80);
-        match u16::decode(&u[0..]) {
-            Ok((m, v)) => {
-                assert_eq!(m, 0x1300);
-                assert_eq!(v.len(), 0);
+        /// The route a reply should take: out through the ingress the message arrived on, if
+        /// it was recorded, then along the return route
+        pub fn to_reply_route(&self) -> Route {
+            let mut route = Route {
+                addresses: Vec::with_capacity(self.return_route.addresses.len() + 1),
+            };
+            route.addresses.extend(self.ingress.iter().cloned());
+            route
+                .addresses
+                .extend(self.return_route.addresses.iter().cloned());
+            route
+        }
+
+        /// Prepend the previous hop to the return route, so a reply retraces the path the
+        /// message took. This is the counterpart of popping the front of the onward route
+        /// when forwarding.
+        pub fn prepend_return_hop(&mut self, addr: Address) -> Result<(), MessageError> {
+            self.return_route.check_room_for_hop()?;
+            self.return_route.addresses.insert(0, addr);
+            Ok(())
+        }
+
+        /// The relay step: move the next hop from the front of the onward route to the front
+        /// of the return route, then encode the message into `out`. If encoding fails the
+        /// routes are put back and nothing is written.
+        pub fn forward_encode(&mut self, out: &mut Vec<u8>) -> Result<(), MessageError> {
+            if self.onward_route.addresses.is_empty() {
+                return Err(MessageError::EmptyRoute);
             }
-            Err(e) => panic!(),
+            self.return_route.check_room_for_hop()?;
+            let hop = self.onward_route.addresses.remove(0);
+            self.return_route.addresses.insert(0, hop);
+            let result = Message::encode(self, out);
+            if result.is_err() {
+                let hop = self.return_route.addresses.remove(0);
+                self.onward_route.addresses.insert(0, hop);
+            }
+            result
         }
 
-        let mut n = 0x1381;
-        let mut u: Vec<u8> = vec![];
-        u16::encode(&mut n, &mut u);
-        assert_eq!(u.len(), 2);
-        assert_eq!(u[1], (0x13 << 1) | 1);
-        assert_eq!(u[0], 0x81);
-        match u16::decode(&u[0..]) {
-            Ok((m, v)) => {
-                assert_eq!(m, 0x1381);
-                assert_eq!(v.len(), 0);
+        /// The total cost of the onward route's hops under `model`
+        pub fn onward_cost(&self, model: &dyn CostModel) -> u64 {
+            self.onward_route
+                .addresses
+                .iter()
+                .map(|a| u64::from(model.hop_cost(a)))
+                .sum()
+        }
+
+        /// Replace the first hop of the return route, for a reply that arrived on a
+        /// different interface than it left from. An empty return route gets the hop added.
+        pub fn rebase_return(&mut self, new_first: Address) {
+            match self.return_route.addresses.first_mut() {
+                Some(first) => *first = new_first,
+                None => self.return_route.addresses.push(new_first),
             }
-            Err(e) => panic!(),
         }
-    }
 
-    #[test]
-    fn message_codec() {
-        let mut onward_addresses: Vec<Address> = vec![];
-        onward_addresses.push(Address::UdpAddress(
-            AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            0x8080,
-        ));
-        onward_addresses.push(Address::UdpAddress(
-            AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
-            0x7070,
-        ));
-        onward_addresses.push(Address::LocalAddress(
-            AddressType::Local,
-            LocalAddress {
-                address: 0x00010203,
-            },
-        ));
+        /// Add a hop to the front of the onward route, making it the next hop
+        pub fn push_onward_front(&mut self, addr: Address) -> Result<(), MessageError> {
+            self.onward_route.check_room_for_hop()?;
+            self.onward_route.addresses.insert(0, addr);
+            Ok(())
+        }
+
+        /// Add a hop to the end of the onward route, making it the destination
+        pub fn push_onward_back(&mut self, addr: Address) -> Result<(), MessageError> {
+            self.onward_route.check_room_for_hop()?;
+            self.onward_route.addresses.push(addr);
+            Ok(())
+        }
+
+        /// Mark whether the sender expects a reply. This is independent of the return route,
+        /// so an empty return route doesn't have to stand in for "don't reply".
+        pub fn set_reply_expected(&mut self, expected: bool) {
+            if expected {
+                self.flags |= FLAG_REPLY_EXPECTED;
+            } else {
+                self.flags &= !FLAG_REPLY_EXPECTED;
+            }
+        }
+
+        /// Whether the sender expects a reply
+        pub fn reply_expected(&self) -> bool {
+            self.flags & FLAG_REPLY_EXPECTED != 0
+        }
+
+        /// Set how urgently the message should be processed, from 0, the lowest
+        pub fn set_priority(&mut self, priority: u8) {
+            self.priority = priority;
+        }
+
+        /// How urgently the message should be processed, from 0, the lowest
+        pub fn priority(&self) -> u8 {
+            self.priority
+        }
+
+        /// The tenant the message belongs to, if it is tagged with one
+        pub fn tenant(&self) -> Option<u32> {
+            self.tenant
+        }
+
+        /// The message's replay protection nonce, if it has one
+        pub fn nonce(&self) -> Option<u64> {
+            self.nonce
+        }
+
+        /// The key a load-balanced relay hashes to pin the message's flow to one backend, if
+        /// it has one
+        pub fn affinity(&self) -> Option<u64> {
+            self.affinity
+        }
+
+        /// Record the local transport address the message arrived on
+        pub fn set_ingress(&mut self, ingress: Address) {
+            self.ingress = Some(ingress);
+        }
+
+        /// The local transport address the message arrived on, if the transport recorded it
+        pub fn ingress(&self) -> Option<&Address> {
+            self.ingress.as_ref()
+        }
+
+        /// For best effort datagram transports: if the encoded message is larger than `mtu`,
+        /// drop its body and mark it as truncated so it can still be sent header only.
+        /// Returns whether the body was dropped. The header alone may still exceed `mtu`.
+        pub fn truncate_to_mtu(&mut self, mtu: usize) -> bool {
+            if self.encoded_len() <= mtu {
+                return false;
+            }
+            self.message_body.clear();
+            self.flags |= FLAG_BODY_TRUNCATED;
+            true
+        }
+
+        /// Whether the body was dropped by `truncate_to_mtu`
+        pub fn body_truncated(&self) -> bool {
+            self.flags & FLAG_BODY_TRUNCATED != 0
+        }
+
+        /// The content type from the message's hints, or `Raw` if it has none
+        pub fn content_type(&self) -> Result<ContentType, MessageError> {
+            let value = match self.hints.iter().find(|(key, _)| *key == HINT_CONTENT_TYPE) {
+                Some((_, value)) => value,
+                None => return Ok(ContentType::Raw),
+            };
+            match value.split_first() {
+                Some((t, [])) => ContentType::try_from(*t),
+                Some((_, rest)) => Err(MessageError::TrailingBytes(rest.len())),
+                None => Err(MessageError::BufferTooShort { needed: 1, got: 0 }),
+            }
+        }
+
+        /// Tag the message with a content type, replacing any it had
+        pub fn set_content_type(&mut self, content_type: ContentType) {
+            self.hints.retain(|(key, _)| *key != HINT_CONTENT_TYPE);
+            self.hints
+                .push((HINT_CONTENT_TYPE, vec![content_type as u8]));
+        }
+
+        /// Replace the body with what `f` makes of it, such as a compressed or encrypted form,
+        /// keeping everything else. The body is moved into `f` rather than copied.
+        pub fn map_body<F: FnOnce(Vec<u8>) -> Result<Vec<u8>, MessageError>>(
+            mut self,
+            f: F,
+        ) -> Result<Message, MessageError> {
+            let body = std::mem::take(&mut self.message_body);
+            self.message_body = f(body)?;
+            Ok(self)
+        }
+
+        /// A hash of the message body alone, for deduplicating messages however they were
+        /// routed. It's FNV-1a, so unlike `DefaultHasher` the value is the same across runs,
+        /// builds and hosts and can be persisted or shared.
+        pub fn body_hash(&self) -> u64 {
+            fnv1a(&self.message_body)
+        }
+
+        /// Render the message as a single JSON object for diagnostics. Addresses use their
+        /// `Display` form and the body is hex encoded. This isn't meant to be parsed back.
+        pub fn to_debug_json(&self) -> String {
+            let mut json = String::from("{\"onward\":");
+            push_json_route(&self.onward_route, &mut json);
+            json.push_str(",\"return\":");
+            push_json_route(&self.return_route, &mut json);
+            json.push_str(",\"body\":\"");
+            for b in self.message_body.iter() {
+                json.push_str(&format!("{:02x}", b));
+            }
+            json.push_str("\"}");
+            json
+        }
+
+        /// Render the message as one line of text for development transports that are piped
+        /// through text tools, such as
+        /// `onward=[udp://127.0.0.1:4000, local://00000001] return=[] ttl=8 body=00`.
+        /// The routes use their `Display` form, except that the characters of worker names
+        /// that would end an address or a field are escaped as `%` and two hex digits, and
+        /// byte strings are hex. The header fields appear only when they aren't the default.
+        /// `decode_text` parses it back for routes of UDP, TCP, local and worker addresses.
+        /// This is separate from the binary codec.
+        pub fn encode_text(&self) -> String {
+            let mut text = String::from("onward=");
+            push_text_route(&self.onward_route, &mut text);
+            text.push_str(" return=");
+            push_text_route(&self.return_route, &mut text);
+            if self.flags != 0 {
+                text.push_str(&format!(" flags={:02x}", self.flags));
+            }
+            if let Some(ttl) = self.ttl {
+                text.push_str(&format!(" ttl={}", ttl));
+            }
+            if self.priority != DEFAULT_PRIORITY {
+                text.push_str(&format!(" priority={}", self.priority));
+            }
+            if let Some(tenant) = self.tenant {
+                text.push_str(&format!(" tenant={}", tenant));
+            }
+            if let Some(nonce) = self.nonce {
+                text.push_str(&format!(" nonce={}", nonce));
+            }
+            if let Some(affinity) = self.affinity {
+                text.push_str(&format!(" affinity={}", affinity));
+            }
+            for (key, value) in self.hints.iter() {
+                text.push_str(&format!(" hint={}:", key));
+                push_hex(value, &mut text);
+            }
+            text.push_str(" body=");
+            push_hex(&self.message_body, &mut text);
+            text
+        }
+
+        /// Parse a message from the form written by `encode_text`
+        pub fn decode_text(s: &str) -> Result<Message, MessageError> {
+            let invalid = |reason: &str| MessageError::InvalidText(format!("{}: {}", s, reason));
+            let mut msg = Message {
+                message_body: vec![],
+                ..Default::default()
+            };
+            let mut rest = s.trim();
+            while !rest.is_empty() {
+                let eq = rest
+                    .find('=')
+                    .ok_or_else(|| invalid("expected key=value"))?;
+                let (key, after) = (&rest[..eq], &rest[eq + 1..]);
+                // A route runs to the first ']' that ends the field, which lets IPv6 hosts
+                // keep their own brackets
+                let end = if after.starts_with('[') {
+                    after
+                        .match_indices(']')
+                        .map(|(i, _)| i + 1)
+                        .find(|&i| after[i..].is_empty() || after[i..].starts_with(' '))
+                        .ok_or_else(|| invalid("unterminated route"))?
+                } else {
+                    after.find(' ').unwrap_or(after.len())
+                };
+                let value = &after[..end];
+                match key {
+                    "onward" => msg.onward_route = parse_text_route(value)?,
+                    "return" => msg.return_route = parse_text_route(value)?,
+                    "flags" => {
+                        msg.flags =
+                            u8::from_str_radix(value, 16).map_err(|_| invalid("bad flags"))?
+                    }
+                    "ttl" => msg.ttl = Some(value.parse().map_err(|_| invalid("bad ttl"))?),
+                    "priority" => {
+                        msg.priority = value.parse().map_err(|_| invalid("bad priority"))?
+                    }
+                    "tenant" => {
+                        msg.tenant = Some(value.parse().map_err(|_| invalid("bad tenant"))?)
+                    }
+                    "nonce" => msg.nonce = Some(value.parse().map_err(|_| invalid("bad nonce"))?),
+                    "affinity" => {
+                        msg.affinity = Some(value.parse().map_err(|_| invalid("bad affinity"))?)
+                    }
+                    "hint" => {
+                        let colon = value.find(':').ok_or_else(|| invalid("bad hint"))?;
+                        let hint_key = value[..colon].parse().map_err(|_| invalid("bad hint"))?;
+                        let hint_value =
+                            parse_hex(&value[colon + 1..]).ok_or_else(|| invalid("bad hint"))?;
+                        msg.hints.push((hint_key, hint_value));
+                    }
+                    "body" => {
+                        msg.message_body = parse_hex(value).ok_or_else(|| invalid("bad body"))?
+                    }
+                    _ => return Err(invalid("unknown key")),
+                }
+                rest = after[end..].trim_start();
+            }
+            Ok(msg)
+        }
+    }
+
+    fn fnv1a(u: &[u8]) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0100_0000_01b3;
+        u.iter().fold(OFFSET_BASIS, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(PRIME)
+        })
+    }
+
+    // Hints are a count followed by each key, as a u16, and its value as a blob
+    fn encode_hints(
+        hints: &[(u16, Vec<u8>)],
+        u: &mut Vec<u8>,
+        format: WireFormat,
+    ) -> Result<(), MessageError> {
+        if hints.is_empty() {
+            return Ok(());
+        }
+        u64::encode(&(hints.len() as u64), u)?;
+        for (key, value) in hints.iter() {
+            if format.fixed_u16 {
+                FixedU16::encode(key, u)?;
+            } else {
+                u16::encode(key, u)?;
+            }
+            encode_blob(value, u)?;
+        }
+        Ok(())
+    }
+
+    fn decode_hints_into<'a>(
+        hints: &mut Vec<(u16, Vec<u8>)>,
+        u: &'a [u8],
+        format: WireFormat,
+    ) -> Result<&'a [u8], MessageError> {
+        let (count, mut w) = u64::decode(u)?;
+        for _ in 0..count {
+            let (key, x) = if format.fixed_u16 {
+                FixedU16::decode(w)?
+            } else {
+                u16::decode(w)?
+            };
+            let (value, x) = decode_blob(x, MAX_BLOB_LEN)?;
+            hints.push((key, value.to_vec()));
+            w = x;
+        }
+        Ok(w)
+    }
+
+    fn skip_hints(u: &[u8], format: WireFormat) -> Result<&[u8], MessageError> {
+        let (count, mut w) = u64::decode(u)?;
+        for _ in 0..count {
+            let x = if format.fixed_u16 {
+                FixedU16::decode(w)?.1
+            } else {
+                u16::decode(w)?.1
+            };
+            w = decode_blob(x, MAX_BLOB_LEN)?.1;
+        }
+        Ok(w)
+    }
+
+    fn hints_encoded_len(hints: &[(u16, Vec<u8>)]) -> usize {
+        if hints.is_empty() {
+            return 0;
+        }
+        varint_u64_len(hints.len() as u64)
+            + hints
+                .iter()
+                .map(|(key, value)| varint_u16_len(*key) + blob_encoded_len(value.len()))
+                .sum::<usize>()
+    }
+
+    // The flags and the optional fields between them and the routes
+    struct FlagsHeader {
+        flags: u8,
+        ext_flags: u8,
+        ttl: Option<u8>,
+        priority: u8,
+        tenant: Option<u32>,
+        nonce: Option<u64>,
+        affinity: Option<u64>,
+    }
+
+    impl FlagsHeader {
+        fn format(&self) -> WireFormat {
+            WireFormat::from_flags(self.flags, self.ext_flags)
+        }
+    }
+
+    // Read the flags and whichever optional fields they say follow
+    fn read_flags(u: &[u8]) -> Result<(FlagsHeader, &[u8]), MessageError> {
+        let (flags, mut w) = match u.split_first() {
+            Some((flags, w)) => (*flags, w),
+            None => return Err(MessageError::BufferTooShort { needed: 1, got: 0 }),
+        };
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(MessageError::UnknownFlags(flags));
+        }
+        let mut ext_flags = 0;
+        if flags & FLAG_EXTENDED != 0 {
+            let (ext, x) = split_at_checked(w, 1)?;
+            ext_flags = ext[0];
+            w = x;
+        }
+        if ext_flags & !KNOWN_EXT_FLAGS != 0 {
+            return Err(MessageError::UnknownFlags(ext_flags));
+        }
+        let mut header = FlagsHeader {
+            flags,
+            ext_flags,
+            ttl: None,
+            priority: DEFAULT_PRIORITY,
+            tenant: None,
+            nonce: None,
+            affinity: None,
+        };
+        if flags & FLAG_TTL != 0 {
+            let (ttl, x) = split_at_checked(w, 1)?;
+            header.ttl = Some(ttl[0]);
+            w = x;
+        }
+        if flags & FLAG_PRIORITY != 0 {
+            let (priority, x) = split_at_checked(w, 1)?;
+            header.priority = priority[0];
+            w = x;
+        }
+        if ext_flags & EXT_FLAG_OPTIONAL_FIELDS != 0 {
+            let (nonce, x) = decode_option::<u64>(w)?;
+            let (tenant, x) = decode_option::<u32>(x)?;
+            let (affinity, x) = decode_option::<u64>(x)?;
+            header.nonce = nonce;
+            header.tenant = tenant;
+            header.affinity = affinity;
+            w = x;
+        }
+        Ok((header, w))
+    }
+
+    // Read the version at the front of a frame, returning the rest of it
+    fn decode_version(u: &[u8]) -> Result<&[u8], MessageError> {
+        let (version, w) = u16::decode(u)?;
+        check_version(version)?;
+        Ok(w)
+    }
+
+    fn check_version(version: u16) -> Result<(), MessageError> {
+        if version != WIRE_PROTOCOL_VERSION {
+            return Err(MessageError::UnsupportedVersion(version));
+        }
+        Ok(())
+    }
+
+    fn diff_route(name: &str, a: &Route, b: &Route) -> Option<String> {
+        for (i, (x, y)) in a.addresses.iter().zip(b.addresses.iter()).enumerate() {
+            if x == y {
+                continue;
+            }
+            let field = match (x, y) {
+                (Address::UdpAddress(_, ip1, port1), Address::UdpAddress(_, ip2, port2))
+                | (Address::TcpAddress(_, ip1, port1), Address::TcpAddress(_, ip2, port2)) => {
+                    if ip1 != ip2 {
+                        format!(".ip: {} != {}", ip1, ip2)
+                    } else {
+                        format!(".port: {} != {}", port1, port2)
+                    }
+                }
+                _ => format!(": {} != {}", x, y),
+            };
+            return Some(format!("{}[{}]{}", name, i, field));
+        }
+        if a.addresses.len() != b.addresses.len() {
+            return Some(format!(
+                "{}.len: {} != {}",
+                name,
+                a.addresses.len(),
+                b.addresses.len()
+            ));
+        }
+        None
+    }
+
+    fn diff_bytes(name: &str, a: &[u8], b: &[u8]) -> Option<String> {
+        for (i, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+            if x != y {
+                return Some(format!("{}[{}]: {:#04x} != {:#04x}", name, i, x, y));
+            }
+        }
+        if a.len() != b.len() {
+            return Some(format!("{}.len: {} != {}", name, a.len(), b.len()));
+        }
+        None
+    }
+
+    fn push_hex(u: &[u8], text: &mut String) {
+        for b in u.iter() {
+            text.push_str(&format!("{:02x}", b));
+        }
+    }
+
+    fn parse_hex(s: &str) -> Option<Vec<u8>> {
+        if s.len() % 2 == 1 || !s.is_ascii() {
+            return None;
+        }
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+            .collect()
+    }
+
+    // A route's `Display` form, `[a, b]`, with worker names escaped so that none of them
+    // holds the ", " between addresses, the space between fields or the closing ']'
+    fn push_text_route(route: &Route, text: &mut String) {
+        text.push('[');
+        for (i, a) in route.addresses.iter().enumerate() {
+            if i > 0 {
+                text.push_str(", ");
+            }
+            match a {
+                Address::NamedLocal(_, name) => {
+                    text.push_str("worker://");
+                    for c in name.chars() {
+                        match c {
+                            c if matches!(c, '%' | ',' | ' ' | '[' | ']')
+                                || c.is_ascii_control() =>
+                            {
+                                text.push_str(&format!("%{:02x}", c as u8))
+                            }
+                            c => text.push(c),
+                        }
+                    }
+                }
+                a => text.push_str(&a.to_string()),
+            }
+        }
+        text.push(']');
+    }
+
+    // The inverse of `push_text_route`
+    fn parse_text_route(s: &str) -> Result<Route, MessageError> {
+        let invalid = |reason: &str| MessageError::InvalidText(format!("{}: {}", s, reason));
+        let inner = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .ok_or_else(|| invalid("bad route"))?;
+        let mut route = Route { addresses: vec![] };
+        if !inner.is_empty() {
+            for a in inner.split(", ") {
+                let address = match a.strip_prefix("worker://") {
+                    Some(escaped) => {
+                        let name = unescape_worker_name(escaped)
+                            .ok_or_else(|| invalid("bad worker name"))?;
+                        check_worker_name(name.as_bytes())?;
+                        Address::named_local(name)
+                    }
+                    None => Address::parse_with_resolver(a, None)?,
+                };
+                route.addresses.push(address);
+            }
+        }
+        Ok(route)
+    }
+
+    fn unescape_worker_name(s: &str) -> Option<String> {
+        let mut name = Vec::with_capacity(s.len());
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                name.push(u8::from_str_radix(hex, 16).ok()?);
+            } else {
+                name.push(b);
+            }
+        }
+        String::from_utf8(name).ok()
+    }
+
+    fn push_json_route(route: &Route, json: &mut String) {
+        json.push('[');
+        for (i, a) in route.addresses.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            push_json_string(&a.to_string(), json);
+        }
+        json.push(']');
+    }
+
+    fn push_json_string(s: &str, json: &mut String) {
+        json.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => json.push_str("\\\""),
+                '\\' => json.push_str("\\\\"),
+                c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+                c => json.push(c),
+            }
+        }
+        json.push('"');
+    }
+
+    /// The length of the HMAC-SHA256 tag `Message::encode_mac` appends to a frame
+    #[cfg(feature = "mac")]
+    pub const MAC_LEN: usize = 32;
+
+    #[cfg(feature = "mac")]
+    type HmacSha256 = hmac::Hmac<sha2::Sha256>;
+
+    #[cfg(feature = "mac")]
+    fn frame_mac(key: &[u8], frame: &[u8]) -> HmacSha256 {
+        use hmac::{Mac, NewMac};
+        let mut mac = HmacSha256::new_varkey(key).expect("HMAC accepts keys of any length");
+        mac.update(frame);
+        mac
+    }
+
+    #[cfg(feature = "mac")]
+    impl Message {
+        /// Encode the message followed by an HMAC-SHA256 of the whole frame under `key`.
+        /// This gives integrity between peers sharing a key, more cheaply than a signature.
+        pub fn encode_mac(&self, key: &[u8], out: &mut Vec<u8>) -> Result<(), MessageError> {
+            use hmac::Mac;
+            let start = out.len();
+            Message::encode(self, out)?;
+            let tag = frame_mac(key, &out[start..]).finalize().into_bytes();
+            out.extend_from_slice(&tag);
+            Ok(())
+        }
+
+        /// Check the trailing HMAC-SHA256 of a frame written by `encode_mac`, then decode
+        /// the frame without it. The tag is compared in constant time.
+        pub fn decode_mac(u: &[u8], key: &[u8]) -> Result<Message, MessageError> {
+            use hmac::Mac;
+            if u.len() < MAC_LEN {
+                return Err(MessageError::BufferTooShort {
+                    needed: MAC_LEN,
+                    got: u.len(),
+                });
+            }
+            let (frame, tag) = u.split_at(u.len() - MAC_LEN);
+            frame_mac(key, frame)
+                .verify(tag)
+                .map_err(|_| MessageError::MacMismatch)?;
+            let (msg, _) = Message::decode(frame)?;
+            Ok(msg)
+        }
+    }
+
+    impl Codec for Message {
+        type Inner = Message;
+        fn encode(msg: &Message, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            Message::encode_with_format(msg, u, WireFormat::default())
+        }
+
+        fn decode(u: &[u8]) -> Result<(Message, &[u8]), MessageError> {
+            Message::decode_with_config(u, &DecodeConfig::default())
+        }
+        fn decode_boxed(u: &[u8]) -> Result<(Box<Message>, &[u8]), MessageError> {
+            let (msg, w) = Message::decode(u)?;
+            Ok((Box::new(msg), w))
+        }
+    }
+
+    /// A decoded message whose body may still borrow from the frame it was decoded from
+    #[derive(Debug)]
+    pub struct MessageCow<'a> {
+        /// Everything but the body, which is left empty
+        pub header: Message,
+        pub body: Cow<'a, [u8]>,
+    }
+
+    impl MessageCow<'_> {
+        /// Make a `Message` that owns its body, copying the body if it is borrowed
+        pub fn into_owned(self) -> Message {
+            let mut msg = self.header;
+            msg.message_body = self.body.into_owned();
+            msg
+        }
+    }
+
+    /// The first byte of an encoded `BodyOnlyMessage`. No `Message` frame starts with it, as
+    /// it can't begin a supported version.
+    pub const BODY_ONLY_MARKER: u8 = 0xff;
+
+    /// A message with no routing at all, for passing bodies within a process. It is encoded
+    /// as `BODY_ONLY_MARKER` followed by the length-prefixed body.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct BodyOnlyMessage(pub Vec<u8>);
+
+    impl Codec for BodyOnlyMessage {
+        type Inner = BodyOnlyMessage;
+        fn encode(msg: &BodyOnlyMessage, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            u.push(BODY_ONLY_MARKER);
+            encode_blob(&msg.0, u)
+        }
+        fn decode(u: &[u8]) -> Result<(BodyOnlyMessage, &[u8]), MessageError> {
+            match u.split_first() {
+                Some((&BODY_ONLY_MARKER, w)) => {
+                    let (body, w) = decode_blob(w, usize::MAX)?;
+                    Ok((BodyOnlyMessage(body.to_vec()), w))
+                }
+                Some(_) => Err(MessageError::NotBodyOnly),
+                None => Err(MessageError::BufferTooShort { needed: 1, got: 0 }),
+            }
+        }
+    }
+
+    impl From<BodyOnlyMessage> for Message {
+        fn from(msg: BodyOnlyMessage) -> Message {
+            Message {
+                message_body: msg.0,
+                ..Default::default()
+            }
+        }
+    }
+
+    /// Fails with `NotBodyOnly` unless the message has nothing but a body: no hops, hints or
+    /// header fields other than the defaults
+    impl TryFrom<Message> for BodyOnlyMessage {
+        type Error = MessageError;
+        fn try_from(msg: Message) -> Result<BodyOnlyMessage, MessageError> {
+            if !msg.onward_route.addresses.is_empty()
+                || !msg.return_route.addresses.is_empty()
+                || !msg.hints.is_empty()
+                || msg.flags != 0
+                || msg.ttl.is_some()
+                || msg.priority != DEFAULT_PRIORITY
+                || msg.tenant.is_some()
+                || msg.nonce.is_some()
+                || msg.affinity.is_some()
+            {
+                return Err(MessageError::NotBodyOnly);
+            }
+            Ok(BodyOnlyMessage(msg.message_body))
+        }
+    }
+
+    /// Orders messages by priority alone, highest first out of a `BinaryHeap`, for schedulers.
+    /// Messages of equal priority compare equal whatever they hold.
+    #[derive(Debug)]
+    pub struct ByPriority(pub Message);
+
+    impl PartialEq for ByPriority {
+        fn eq(&self, other: &Self) -> bool {
+            self.0.priority == other.0.priority
+        }
+    }
+
+    impl Eq for ByPriority {}
+
+    impl PartialOrd for ByPriority {
+        fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for ByPriority {
+        fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+            self.0.priority.cmp(&other.0.priority)
+        }
+    }
+
+    /// Keeps tenants apart on a relay, accepting only the messages of the tenants it serves.
+    /// Messages without a tenant belong to none of them.
+    #[derive(Clone, Debug, Default)]
+    pub struct TenantFilter {
+        tenants: HashSet<u32>,
+    }
+
+    impl TenantFilter {
+        pub fn new(tenants: impl IntoIterator<Item = u32>) -> TenantFilter {
+            TenantFilter {
+                tenants: tenants.into_iter().collect(),
+            }
+        }
+
+        /// Whether the message belongs to one of the filter's tenants
+        pub fn accepts(&self, msg: &Message) -> bool {
+            matches!(msg.tenant, Some(t) if self.tenants.contains(&t))
+        }
+    }
+
+    /// How far behind the highest nonce seen a `ReplayWindow` still accepts a nonce
+    pub const REPLAY_WINDOW_LEN: u64 = 64;
+
+    /// Detects replayed messages on a secure channel from their nonces. It remembers which of
+    /// the last `REPLAY_WINDOW_LEN` nonces up to the highest seen have arrived, so messages
+    /// may arrive out of order within the window, and rejects anything older.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct ReplayWindow {
+        highest: Option<u64>,
+        // Bit n is set when the nonce n below the highest has been seen
+        seen: u64,
+    }
+
+    impl ReplayWindow {
+        pub fn new() -> ReplayWindow {
+            ReplayWindow::default()
+        }
+
+        /// Accept the message's nonce if it hasn't been seen and is recent enough, and
+        /// remember it. A message without a nonce is rejected.
+        pub fn check_and_record(&mut self, msg: &Message) -> Result<(), MessageError> {
+            let nonce = msg.nonce.ok_or(MessageError::MissingNonce)?;
+            let highest = match self.highest {
+                Some(highest) => highest,
+                None => {
+                    self.highest = Some(nonce);
+                    self.seen = 1;
+                    return Ok(());
+                }
+            };
+            if nonce > highest {
+                let shift = nonce - highest;
+                self.seen = if shift >= REPLAY_WINDOW_LEN {
+                    0
+                } else {
+                    self.seen << shift
+                };
+                self.seen |= 1;
+                self.highest = Some(nonce);
+                return Ok(());
+            }
+            let age = highest - nonce;
+            if age >= REPLAY_WINDOW_LEN {
+                return Err(MessageError::StaleNonce(nonce));
+            }
+            if self.seen & (1 << age) != 0 {
+                return Err(MessageError::ReplayedNonce(nonce));
+            }
+            self.seen |= 1 << age;
+            Ok(())
+        }
+    }
+
+    /// Recycles messages so their route and body allocations can be reused, for servers
+    /// handling many messages. Messages are handed out as `PooledMessage` guards that return
+    /// them to the pool when dropped.
+    pub struct MessagePool {
+        idle: std::sync::Mutex<Vec<Message>>,
+        max_idle: usize,
+    }
+
+    impl MessagePool {
+        /// A pool keeping at most `max_idle` released messages for reuse
+        pub fn new(max_idle: usize) -> MessagePool {
+            MessagePool {
+                idle: std::sync::Mutex::new(vec![]),
+                max_idle,
+            }
+        }
+
+        /// Take an empty message from the pool, allocating a new one if none are idle
+        pub fn acquire(&self) -> PooledMessage<'_> {
+            let msg = self.idle.lock().unwrap().pop().unwrap_or_else(|| Message {
+                message_body: vec![],
+                ..Default::default()
+            });
+            PooledMessage {
+                msg: Some(msg),
+                pool: self,
+            }
+        }
+
+        /// Decode a message into one taken from the pool
+        pub fn decode(&self, u: &[u8]) -> Result<PooledMessage<'_>, MessageError> {
+            self.decode_with_config(u, &DecodeConfig::default())
+        }
+
+        /// Decode a message into one taken from the pool, applying the limits in `config`
+        pub fn decode_with_config(
+            &self,
+            u: &[u8],
+            config: &DecodeConfig,
+        ) -> Result<PooledMessage<'_>, MessageError> {
+            let mut msg = self.acquire();
+            msg.decode_into_with_config(u, config)?;
+            Ok(msg)
+        }
+
+        /// How many released messages are waiting to be reused
+        pub fn idle(&self) -> usize {
+            self.idle.lock().unwrap().len()
+        }
+
+        fn release(&self, mut msg: Message) {
+            msg.onward_route.addresses.clear();
+            msg.return_route.addresses.clear();
+            msg.message_body.clear();
+            msg.flags = 0;
+            msg.hints.clear();
+            msg.ttl = None;
+            msg.priority = DEFAULT_PRIORITY;
+            msg.tenant = None;
+            msg.nonce = None;
+            msg.affinity = None;
+            msg.ingress = None;
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < self.max_idle {
+                idle.push(msg);
+            }
+        }
+    }
+
+    /// A message borrowed from a `MessagePool`, returned to it on drop
+    pub struct PooledMessage<'a> {
+        msg: Option<Message>,
+        pool: &'a MessagePool,
+    }
+
+    impl std::ops::Deref for PooledMessage<'_> {
+        type Target = Message;
+        fn deref(&self) -> &Message {
+            self.msg.as_ref().unwrap()
+        }
+    }
+
+    impl std::ops::DerefMut for PooledMessage<'_> {
+        fn deref_mut(&mut self) -> &mut Message {
+            self.msg.as_mut().unwrap()
+        }
+    }
+
+    impl Drop for PooledMessage<'_> {
+        fn drop(&mut self) {
+            if let Some(msg) = self.msg.take() {
+                self.pool.release(msg);
+            }
+        }
+    }
+
+    /// The fewest bytes a frame can take: the version, the flags and two empty routes
+    const MIN_FRAME_LEN: u64 = 4;
+
+    /// Splits a byte stream, such as a TCP connection, into messages written to it with
+    /// `Message::encode_length_prefixed`. Bytes are pushed as they arrive and messages taken
+    /// out once complete.
+    #[derive(Clone, Debug)]
+    pub struct MessageStreamDecoder {
+        buffer: Vec<u8>,
+        /// The longest frame accepted. A longer length prefix is treated as corruption.
+        pub max_frame_len: usize,
+        /// Resynchronize past a malformed frame instead of returning its error
+        pub skip_bad_frames: bool,
+        /// The limits each frame is decoded with
+        pub config: DecodeConfig,
+    }
+
+    impl Default for MessageStreamDecoder {
+        fn default() -> MessageStreamDecoder {
+            MessageStreamDecoder {
+                buffer: vec![],
+                max_frame_len: 0x10000,
+                skip_bad_frames: false,
+                config: DecodeConfig::default(),
+            }
+        }
+    }
+
+    impl MessageStreamDecoder {
+        pub fn new() -> MessageStreamDecoder {
+            MessageStreamDecoder::default()
+        }
+
+        /// Add bytes read from the stream
+        pub fn push(&mut self, bytes: &[u8]) {
+            self.buffer.extend_from_slice(bytes);
+        }
+
+        /// How many bytes are waiting to be decoded
+        pub fn buffered(&self) -> usize {
+            self.buffer.len()
+        }
+
+        /// Take the next message out of the buffer, or None if it hasn't all arrived yet. A
+        /// malformed frame is left in the buffer and its error returned, so the caller can
+        /// `resync`, unless `skip_bad_frames` is set, in which case it is skipped.
+        pub fn decode_next(&mut self) -> Result<Option<Message>, MessageError> {
+            loop {
+                match self.decode_frame() {
+                    Err(_) if self.skip_bad_frames => self.resync(),
+                    result => return result,
+                }
+            }
+        }
+
+        fn decode_frame(&mut self) -> Result<Option<Message>, MessageError> {
+            let (len, frame) = match u64::decode(&self.buffer) {
+                Ok(decoded) => decoded,
+                Err(MessageError::BufferTooShort { .. }) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            if len > self.max_frame_len as u64 {
+                return Err(MessageError::ValueTooLarge);
+            }
+            let len = len as usize;
+            if frame.len() < len {
+                return Ok(None);
+            }
+            let (msg, _) = Message::decode_with_config(&frame[..len], &self.config)?;
+            let consumed = self.buffer.len() - frame.len() + len;
+            self.buffer.drain(..consumed);
+            Ok(Some(msg))
+        }
+
+        /// Drop at least one byte from the front of the buffer, then everything up to the next
+        /// place a frame could plausibly start: a length prefix in range followed by a
+        /// supported version and known flags. Bytes that haven't arrived yet are given the
+        /// benefit of the doubt.
+        pub fn resync(&mut self) {
+            let skip = (1..=self.buffer.len())
+                .find(|i| self.plausible_frame_start(&self.buffer[*i..]))
+                .unwrap_or(0);
+            self.buffer.drain(..skip);
+        }
+
+        fn plausible_frame_start(&self, u: &[u8]) -> bool {
+            let (len, frame) = match u64::decode(u) {
+                Ok(decoded) => decoded,
+                Err(MessageError::BufferTooShort { .. }) => return true,
+                Err(_) => return false,
+            };
+            if len < MIN_FRAME_LEN || len > self.max_frame_len as u64 {
+                return false;
+            }
+            let frame = &frame[..frame.len().min(len as usize)];
+            match decode_version(frame) {
+                Ok(w) => match w {
+                    [flags, ..] if flags & !KNOWN_FLAGS != 0 => false,
+                    [flags, ext_flags, ..] if flags & FLAG_EXTENDED != 0 => {
+                        ext_flags & !KNOWN_EXT_FLAGS == 0
+                    }
+                    _ => true,
+                },
+                Err(MessageError::BufferTooShort { .. }) => true,
+                Err(_) => false,
+            }
+        }
+    }
+
+    /* Addresses */
+    #[repr(C)]
+    pub enum AddressType {
+        Local = 0,
+        Tcp = 1,
+        Udp = 2,
+        Tunnel = 3,
+        NamedLocal = 4,
+        Aliased = 5,
+    }
+
+    impl Clone for AddressType {
+        fn clone(&self) -> Self {
+            return match self {
+                AddressType::Local => AddressType::Local,
+                AddressType::Tcp => AddressType::Tcp,
+                AddressType::Udp => AddressType::Udp,
+                AddressType::Tunnel => AddressType::Tunnel,
+                AddressType::NamedLocal => AddressType::NamedLocal,
+                AddressType::Aliased => AddressType::Aliased,
+            };
+        }
+    }
+
+    impl std::fmt::Debug for AddressType {
+        fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), std::fmt::Error> {
+            let s: String;
+            match self {
+                AddressType::Local => {
+                    s = "Local".to_string();
+                }
+                AddressType::Tcp => {
+                    s = "Tcp".to_string();
+                }
+                AddressType::Udp => {
+                    s = "Udp".to_string();
+                }
+                AddressType::Tunnel => {
+                    s = "Tunnel".to_string();
+                }
+                AddressType::NamedLocal => {
+                    s = "NamedLocal".to_string();
+                }
+                AddressType::Aliased => {
+                    s = "Aliased".to_string();
+                }
+            }
+            f.debug_struct("AddressType").field("Type", &s).finish();
+            Ok(())
+        }
+    }
+
+    impl Copy for AddressType {}
+
+    impl AddressType {
+        /// The fewest bytes an address of this type takes in the default wire format,
+        /// including its type byte. IP addresses are counted as IPv4.
+        pub fn min_encoded_len(&self) -> usize {
+            match self {
+                AddressType::Local => 1 + 4,
+                AddressType::Tcp | AddressType::Udp => 1 + 1 + 4 + 2,
+                // A length byte and an empty nested route
+                AddressType::Tunnel => 1 + 1 + 1,
+                // A length byte and a name of at least one byte
+                AddressType::NamedLocal => 1 + 1 + 1,
+                // The smallest address to wrap is a custom one that is only its type byte,
+                // then an empty name
+                AddressType::Aliased => 1 + 1 + 1,
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq, Hash)]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    pub struct LocalAddress {
+        pub address: u32,
+    }
+
+    // ToDo: implement Copy, Clone
+    #[repr(C)]
+    #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum Address {
+        LocalAddress(AddressType, LocalAddress),
+        TcpAddress(AddressType, IpAddr, u16),
+        UdpAddress(AddressType, IpAddr, u16),
+        /// A route carried as a single hop, encoded as a length-prefixed nested route
+        Tunnel(Box<Route>),
+        /// A local worker addressed by name rather than number, encoded as the
+        /// length-prefixed UTF-8 name. Names can't be empty or longer than
+        /// `MAX_WORKER_NAME_LEN` bytes.
+        NamedLocal(AddressType, String),
+        /// An address with a display name for logs. It routes exactly like the address it
+        /// wraps, and is encoded as that address followed by the length-prefixed name.
+        Aliased(Box<Address>, String),
+        /// An address type defined outside this crate, holding its type byte and payload.
+        /// It is encoded and decoded by the codec registered for the type byte in an
+        /// `AddressCodecRegistry`.
+        Custom(u8, Vec<u8>),
+    }
+
+    /// The IP version of a TCP or UDP address
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    pub enum AddressFamily {
+        V4,
+        V6,
+    }
+
+    pub enum HostAddressType {
+        Ipv4 = 0,
+        Ipv6 = 1,
+    }
+
+    impl PartialEq for AddressType {
+        fn eq(&self, other: &Self) -> bool {
+            let t: u8 = *self as u8;
+            let o = *other as u8;
+            return t == o;
+        }
+
+        fn ne(&self, other: &Self) -> bool {
+            let t = *self as u8;
+            let o = *other as u8;
+            return t != o;
+        }
+    }
+
+    impl Eq for AddressType {}
+
+    impl std::hash::Hash for AddressType {
+        fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+            (*self as u8).hash(state);
+        }
+    }
+
+    impl TryFrom<u8> for HostAddressType {
+        type Error = MessageError;
+        fn try_from(data: u8) -> Result<Self, Self::Error> {
+            match data {
+                0 => Ok(HostAddressType::Ipv4),
+                1 => Ok(HostAddressType::Ipv6),
+                _ => Err(MessageError::UnknownHostAddressType(data)),
+            }
+        }
+    }
+
+    impl TryFrom<u8> for AddressType {
+        type Error = MessageError;
+        fn try_from(data: u8) -> Result<Self, Self::Error> {
+            match data {
+                0 => Ok(AddressType::Local),
+                1 => Ok(AddressType::Tcp),
+                2 => Ok(AddressType::Udp),
+                3 => Ok(AddressType::Tunnel),
+                4 => Ok(AddressType::NamedLocal),
+                5 => Ok(AddressType::Aliased),
+                _ => Err(MessageError::UnknownAddressType(data)),
+            }
+        }
+    }
+
+    impl Codec for Address {
+        type Inner = Address;
+        fn encode(a: &Address, v: &mut Vec<u8>) -> Result<(), MessageError> {
+            Address::encode_nested(a, v, WireFormat::default(), None, 0)
+        }
+        fn decode(u: &[u8]) -> Result<(Address, &[u8]), MessageError> {
+            Address::decode_with_config(u, &DecodeConfig::default())
+        }
+    }
+
+    // Tunnel hops make the address and route codecs mutually recursive, so both carry the
+    // current nesting depth. Encoding refuses to go deeper than MAX_TUNNEL_DEPTH, decoding
+    // deeper than the configured max_depth.
+    impl Address {
+        /// A UDP address, from an IP or the four bytes of an IPv4 address
+        pub fn udp(ip: impl Into<IpAddr>, port: u16) -> Address {
+            Address::UdpAddress(AddressType::Udp, ip.into(), port)
+        }
+
+        /// A UDP address on the IPv4 loopback interface
+        pub fn udp_loopback(port: u16) -> Address {
+            Address::UdpAddress(AddressType::Udp, IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+        }
+
+        /// A TCP address on the IPv4 loopback interface
+        pub fn tcp_loopback(port: u16) -> Address {
+            Address::TcpAddress(AddressType::Tcp, IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+        }
+
+        /// The address of a local worker
+        pub fn local(address: u32) -> Address {
+            Address::LocalAddress(AddressType::Local, LocalAddress { address })
+        }
+
+        /// The address of a local worker known by name
+        pub fn named_local(name: impl Into<String>) -> Address {
+            Address::NamedLocal(AddressType::NamedLocal, name.into())
+        }
+
+        /// A UDP or TCP address from an IP held as raw bytes, 4 for IPv4 or 16 for IPv6, as
+        /// FFI and embedded callers tend to have it
+        pub fn from_parts(
+            ty: AddressType,
+            ip_bytes: &[u8],
+            port: u16,
+        ) -> Result<Address, MessageError> {
+            let ip = match ip_bytes.len() {
+                4 => IpAddr::V4(Ipv4Addr::new(
+                    ip_bytes[0],
+                    ip_bytes[1],
+                    ip_bytes[2],
+                    ip_bytes[3],
+                )),
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(ip_bytes);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                n => {
+                    return Err(MessageError::InvalidAddress(format!(
+                        "of {} bytes: an IP address is 4 or 16 bytes",
+                        n
+                    )))
+                }
+            };
+            match ty {
+                AddressType::Udp => Ok(Address::UdpAddress(ty, ip, port)),
+                AddressType::Tcp => Ok(Address::TcpAddress(ty, ip, port)),
+                _ => Err(MessageError::InvalidAddress(format!(
+                    "type {}: only UDP and TCP addresses have an IP",
+                    ty as u8
+                ))),
+            }
+        }
+
+        /// Whether both addresses are remote and point at the same IP and port, whether over
+        /// TCP or UDP. Unlike `==`, this ignores the transport.
+        pub fn same_endpoint(&self, other: &Address) -> bool {
+            match (self.endpoint(), other.endpoint()) {
+                (Some(a), Some(b)) => a == b,
+                _ => false,
+            }
+        }
+
+        /// The IP version of a TCP or UDP address, looking through aliases. Other addresses,
+        /// including tunnels, have none.
+        pub fn family(&self) -> Option<AddressFamily> {
+            match self.endpoint()?.0 {
+                IpAddr::V4(_) => Some(AddressFamily::V4),
+                IpAddr::V6(_) => Some(AddressFamily::V6),
+            }
+        }
+
+        // The built-in type of the address, or None for a custom address
+        fn builtin_type(&self) -> Option<AddressType> {
+            match self {
+                Address::LocalAddress(t, _)
+                | Address::TcpAddress(t, _, _)
+                | Address::UdpAddress(t, _, _)
+                | Address::NamedLocal(t, _) => Some(*t),
+                Address::Tunnel(_) => Some(AddressType::Tunnel),
+                Address::Aliased(_, _) => Some(AddressType::Aliased),
+                Address::Custom(_, _) => None,
+            }
+        }
+
+        fn endpoint(&self) -> Option<(IpAddr, u16)> {
+            match self.unaliased() {
+                Address::UdpAddress(_, ip, port) | Address::TcpAddress(_, ip, port) => {
+                    Some((*ip, *port))
+                }
+                _ => None,
+            }
+        }
+
+        /// Whether a node at `local` could dial this address, judged by IP scope. Loopback
+        /// addresses are only reachable from loopback, private addresses only from the same
+        /// private range, and global addresses from anywhere. Addresses without an IP, such as
+        /// local workers, are always considered reachable. A tunnel is judged by its first hop.
+        pub fn is_reachable_from(&self, local: &Address) -> bool {
+            let target = match self.first_ip() {
+                Some(ip) => ip_scope(&ip),
+                None => return true,
+            };
+            let source = match local.first_ip() {
+                Some(ip) => ip_scope(&ip),
+                None => return true,
+            };
+            match target {
+                IpScope::Global => true,
+                _ => target == source,
+            }
+        }
+
+        // The IP this address is dialed at, looking through aliases and into tunnels
+        fn first_ip(&self) -> Option<IpAddr> {
+            match self.unaliased() {
+                Address::Tunnel(route) => route.flatten().addresses.first()?.first_ip(),
+                a => a.endpoint().map(|(ip, _)| ip),
+            }
+        }
+
+        /// Attach a display name to an address without changing where it routes
+        pub fn aliased(address: Address, alias: impl Into<String>) -> Address {
+            Address::Aliased(Box::new(address), alias.into())
+        }
+
+        /// The address this one routes to, looking through any aliases
+        pub fn unaliased(&self) -> &Address {
+            let mut a = self;
+            while let Address::Aliased(inner, _) = a {
+                a = inner;
+            }
+            a
+        }
+
+        /// How many bytes `encode` writes for this address. For a custom address this is its
+        /// type byte plus its payload, which is only exact if its codec writes the payload
+        /// as is.
+        pub fn encoded_len(&self) -> usize {
+            match self {
+                Address::LocalAddress(_, _) => 1 + 4,
+                Address::UdpAddress(_, ip, _) | Address::TcpAddress(_, ip, _) => {
+                    let ip_len = match ip {
+                        IpAddr::V4(_) => 4,
+                        IpAddr::V6(_) => 16,
+                    };
+                    1 + 1 + ip_len + 2
+                }
+                Address::Tunnel(route) => 1 + blob_encoded_len(route.encoded_len()),
+                Address::NamedLocal(_, name) => 1 + blob_encoded_len(name.len()),
+                Address::Aliased(inner, alias) => {
+                    1 + inner.encoded_len() + blob_encoded_len(alias.len())
+                }
+                Address::Custom(_, data) => 1 + data.len(),
+            }
+        }
+
+        /// Decode an address, applying the limits in `config`
+        pub fn decode_with_config<'a>(
+            u: &'a [u8],
+            config: &DecodeConfig,
+        ) -> Result<(Address, &'a [u8]), MessageError> {
+            Address::decode_nested(u, config, WireFormat::default(), 0)
+        }
+
+        /// Encode an address using an alternative wire format
+        pub fn encode_with_format(
+            a: &Address,
+            v: &mut Vec<u8>,
+            format: WireFormat,
+        ) -> Result<(), MessageError> {
+            Address::encode_nested(a, v, format, None, 0)
+        }
+
+        /// Encode an address, using the codecs in `registry` for custom addresses
+        pub fn encode_with_registry(
+            a: &Address,
+            v: &mut Vec<u8>,
+            registry: &AddressCodecRegistry,
+        ) -> Result<(), MessageError> {
+            Address::encode_nested(a, v, WireFormat::default(), Some(registry), 0)
+        }
+
+        /// Decode an address, handing address types the crate doesn't know to `registry`
+        pub fn decode_with_registry<'a>(
+            u: &'a [u8],
+            registry: &Arc<AddressCodecRegistry>,
+        ) -> Result<(Address, &'a [u8]), MessageError> {
+            let config = DecodeConfig {
+                registry: Some(Arc::clone(registry)),
+                ..Default::default()
+            };
+            Address::decode_with_config(u, &config)
+        }
+
+        /// Decode an address encoded with an alternative wire format
+        pub fn decode_with_format(
+            u: &[u8],
+            format: WireFormat,
+        ) -> Result<(Address, &[u8]), MessageError> {
+            Address::decode_nested(u, &DecodeConfig::default(), format, 0)
+        }
+
+        fn encode_nested(
+            a: &Address,
+            v: &mut Vec<u8>,
+            format: WireFormat,
+            registry: Option<&AddressCodecRegistry>,
+            depth: usize,
+        ) -> Result<(), MessageError> {
+            match a {
+                Address::LocalAddress(mut t, a) => {
+                    v.push(t as u8);
+                    LocalAddress::encode(a, v)?;
+                }
+                Address::UdpAddress(mut t, ipa, mut port) => {
+                    v.push(t as u8);
+                    IpAddr::encode(ipa, v)?;
+                    encode_port(port, v, format)?;
+                }
+                Address::TcpAddress(mut t, ipa, mut port) => {
+                    v.push(t as u8);
+                    IpAddr::encode(ipa, v)?;
+                    encode_port(port, v, format)?;
+                }
+                Address::Tunnel(route) => {
+                    if depth >= MAX_TUNNEL_DEPTH {
+                        return Err(MessageError::NestingTooDeep);
+                    }
+                    let mut inner = vec![];
+                    Route::encode_nested(route, &mut inner, format, registry, depth + 1)?;
+                    v.push(AddressType::Tunnel as u8);
+                    encode_blob(&inner, v)?;
+                }
+                Address::NamedLocal(_, name) => {
+                    check_worker_name(name.as_bytes())?;
+                    v.push(AddressType::NamedLocal as u8);
+                    encode_blob(name.as_bytes(), v)?;
+                }
+                Address::Aliased(inner, alias) => {
+                    if depth >= MAX_TUNNEL_DEPTH {
+                        return Err(MessageError::NestingTooDeep);
+                    }
+                    v.push(AddressType::Aliased as u8);
+                    Address::encode_nested(inner, v, format, registry, depth + 1)?;
+                    encode_blob(alias.as_bytes(), v)?;
+                }
+                Address::Custom(t, data) => match registry {
+                    Some(registry) => registry.encode(*t, data, v)?,
+                    None => return Err(MessageError::UnknownAddressType(*t)),
+                },
+            }
+            Ok(())
+        }
+        fn decode_nested<'a>(
+            u: &'a [u8],
+            config: &DecodeConfig,
+            format: WireFormat,
+            depth: usize,
+        ) -> Result<(Address, &'a [u8]), MessageError> {
+            if u.is_empty() {
+                return Err(MessageError::BufferTooShort { needed: 1, got: 0 });
+            }
+            let address_type = match AddressType::try_from(u[0]) {
+                Ok(t) => t,
+                Err(e) => {
+                    return match &config.registry {
+                        Some(registry) => registry.decode(u[0], &u[1..]),
+                        None => Err(e),
+                    }
+                }
+            };
+            let mut needed = address_type.min_encoded_len();
+            if format.varint_port_encoding()
+                && matches!(address_type, AddressType::Tcp | AddressType::Udp)
+            {
+                // A variable-length port may take a single byte
+                needed -= 1;
+            }
+            if u.len() < needed {
+                return Err(MessageError::BufferTooShort {
+                    needed,
+                    got: u.len(),
+                });
+            }
+            match address_type {
+                AddressType::Local => {
+                    let (la, v) = LocalAddress::decode(&u[1..])?;
+                    let address = Address::LocalAddress(AddressType::Local, la);
+                    Ok((address, v))
+                }
+                AddressType::Tcp => {
+                    let (ipa, v) = IpAddr::decode(&u[1..])?;
+                    let (port, v) = decode_port(v, format)?;
+                    let address = Address::TcpAddress(AddressType::Tcp, ipa, port);
+                    Ok((address, v))
+                }
+                AddressType::Udp => {
+                    let (ipa, v) = IpAddr::decode(&u[1..])?;
+                    let (port, v) = decode_port(v, format)?;
+                    let address = Address::UdpAddress(AddressType::Udp, ipa, port);
+                    Ok((address, v))
+                }
+                AddressType::Tunnel => {
+                    if depth >= config.max_depth {
+                        return Err(MessageError::NestingTooDeep);
+                    }
+                    let (inner, v) = decode_blob(&u[1..], MAX_BLOB_LEN)?;
+                    let (route, rest) = Route::decode_nested(inner, config, format, depth + 1)?;
+                    if !rest.is_empty() {
+                        return Err(MessageError::TrailingBytes(rest.len()));
+                    }
+                    Ok((Address::Tunnel(Box::new(route)), v))
+                }
+                AddressType::NamedLocal => {
+                    let (name, v) = decode_blob(&u[1..], MAX_WORKER_NAME_LEN)?;
+                    check_worker_name(name)?;
+                    let name =
+                        String::from_utf8(name.to_vec()).map_err(|_| MessageError::InvalidUtf8)?;
+                    Ok((Address::NamedLocal(AddressType::NamedLocal, name), v))
+                }
+                AddressType::Aliased => {
+                    if depth >= config.max_depth {
+                        return Err(MessageError::NestingTooDeep);
+                    }
+                    let (inner, v) = Address::decode_nested(&u[1..], config, format, depth + 1)?;
+                    let (alias, v) = decode_blob(v, MAX_BLOB_LEN)?;
+                    let alias =
+                        String::from_utf8(alias.to_vec()).map_err(|_| MessageError::InvalidUtf8)?;
+                    Ok((Address::Aliased(Box::new(inner), alias), v))
+                }
+            }
+        }
+    }
+
+    fn check_worker_name(name: &[u8]) -> Result<(), MessageError> {
+        if name.is_empty() {
+            return Err(MessageError::InvalidAddress(
+                "worker://: empty worker name".to_string(),
+            ));
+        }
+        if name.len() > MAX_WORKER_NAME_LEN {
+            return Err(MessageError::ValueTooLarge);
+        }
+        Ok(())
+    }
+
+    // How many bytes the variable-length u16 codec uses for a value
+    fn varint_u16_len(v: u16) -> usize {
+        if v < 0x80 {
+            1
+        } else {
+            2
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum IpScope {
+        Loopback,
+        // Private ranges, told apart by their first byte
+        Private(u8),
+        Global,
+    }
+
+    fn ip_scope(ip: &IpAddr) -> IpScope {
+        if ip.is_loopback() {
+            return IpScope::Loopback;
+        }
+        match ip {
+            IpAddr::V4(ip4) => match ip4.octets() {
+                [10, ..] => IpScope::Private(10),
+                [172, b, ..] if b & 0xf0 == 16 => IpScope::Private(172),
+                [192, 168, ..] => IpScope::Private(192),
+                _ => IpScope::Global,
+            },
+            // Unique local addresses, fc00::/7
+            IpAddr::V6(ip6) if ip6.segments()[0] & 0xfe00 == 0xfc00 => IpScope::Private(0xfc),
+            IpAddr::V6(_) => IpScope::Global,
+        }
+    }
+
+    fn encode_port(port: u16, v: &mut Vec<u8>, format: WireFormat) -> Result<(), MessageError> {
+        if format.varint_port_encoding() {
+            u16::encode(&port, v)
+        } else {
+            FixedU16::encode(&port, v)
+        }
+    }
+
+    fn decode_port(u: &[u8], format: WireFormat) -> Result<(u16, &[u8]), MessageError> {
+        if format.varint_port_encoding() {
+            return u16::decode(u);
+        }
+        FixedU16::decode(u)
+    }
+
+    impl Codec for IpAddr {
+        type Inner = IpAddr;
+        fn encode(ip: &IpAddr, v: &mut Vec<u8>) -> Result<(), MessageError> {
+            match ip {
+                std::net::IpAddr::V4(ip4) => {
+                    v.push(HostAddressType::Ipv4 as u8);
+                    v.extend_from_slice(ip4.octets().as_ref());
+                }
+                std::net::IpAddr::V6(ip6) => {
+                    v.push(HostAddressType::Ipv6 as u8);
+                    v.extend_from_slice(ip6.octets().as_ref());
+                }
+            }
+            Ok(())
+        }
+        fn decode(u: &[u8]) -> Result<(IpAddr, &[u8]), MessageError> {
+            let (host, w) = split_at_checked(u, 1)?;
+            match HostAddressType::try_from(host[0])? {
+                HostAddressType::Ipv4 => {
+                    let (addr, w) = split_at_checked(w, 4)?;
+                    let ip4 = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+                    let ip_addr = IpAddr::V4(ip4);
+                    Ok((ip_addr, w))
+                }
+                HostAddressType::Ipv6 => {
+                    let (addr, w) = split_at_checked(w, 16)?;
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(addr);
+                    Ok((IpAddr::V6(Ipv6Addr::from(octets)), w))
+                }
+            }
+        }
+    }
+
+    impl LocalAddress {
+        /// The zero address, which stands for an unspecified worker rather than a real one
+        pub const NULL: LocalAddress = LocalAddress { address: 0 };
+
+        /// Whether this is the unspecified `NULL` address
+        pub fn is_null(&self) -> bool {
+            *self == LocalAddress::NULL
+        }
+
+        /// Decode a local address like `decode`, but reject the `NULL` address, for contexts
+        /// where a real worker is required
+        pub fn decode_nonzero(u: &[u8]) -> Result<(LocalAddress, &[u8]), MessageError> {
+            let (la, w) = LocalAddress::decode(u)?;
+            if la.is_null() {
+                return Err(MessageError::NullLocalAddress);
+            }
+            Ok((la, w))
+        }
+    }
+
+    impl Codec for LocalAddress {
+        type Inner = LocalAddress;
+        fn encode(la: &LocalAddress, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            for le_byte in la.address.to_le_bytes().iter() {
+                u.push(*le_byte);
+            }
+            Ok(())
+        }
+        fn decode(u: &[u8]) -> Result<(LocalAddress, &[u8]), MessageError> {
+            let (b, w) = split_at_checked(u, 4)?;
+            Ok((
+                LocalAddress {
+                    address: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                },
+                w,
+            ))
+        }
+    }
+
+    /* Routes */
+    #[derive(Debug, Default, PartialEq, Eq, Hash)]
+    #[repr(C)]
+    pub struct Route {
+        pub addresses: Vec<Address>,
+    }
+
+    impl std::fmt::Display for Address {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Address::LocalAddress(_, la) => write!(f, "local://{:08x}", la.address),
+                Address::TcpAddress(_, ip, port) => {
+                    write!(f, "tcp://{}", std::net::SocketAddr::new(*ip, *port))
+                }
+                Address::UdpAddress(_, ip, port) => {
+                    write!(f, "udp://{}", std::net::SocketAddr::new(*ip, *port))
+                }
+                Address::Tunnel(route) => write!(f, "tunnel://{}", route),
+                Address::NamedLocal(_, name) => write!(f, "worker://{}", name),
+                Address::Aliased(_, alias) => write!(f, "{}", alias),
+                Address::Custom(t, data) => {
+                    write!(f, "custom{}://", t)?;
+                    for b in data.iter() {
+                        write!(f, "{:02x}", b)?;
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Looks up the IP address of a network interface by name, for parsing addresses written
+    /// with an interface in place of an IP, such as `udp://eth0:5000`
+    pub trait InterfaceResolver {
+        fn resolve(&self, interface: &str) -> Option<IpAddr>;
+    }
+
+    /// Resolves interface names with the operating system. An interface with several
+    /// addresses resolves to its first IPv4 address, if it has one.
+    #[cfg(feature = "interface-resolve")]
+    pub struct OsInterfaceResolver;
+
+    #[cfg(feature = "interface-resolve")]
+    impl InterfaceResolver for OsInterfaceResolver {
+        fn resolve(&self, interface: &str) -> Option<IpAddr> {
+            let interfaces = get_if_addrs::get_if_addrs().ok()?;
+            let ips: Vec<IpAddr> = interfaces
+                .iter()
+                .filter(|i| i.name == interface)
+                .map(|i| i.ip())
+                .collect();
+            ips.iter()
+                .find(|ip| ip.is_ipv4())
+                .or_else(|| ips.first())
+                .copied()
+        }
+    }
+
+    impl Address {
+        /// Parse an address from its `Display` form: `udp://` or `tcp://` with an IP and
+        /// port, `local://` with the worker's address in hex, or `worker://` with its name.
+        /// With a `resolver`, an interface name may stand in for the IP and is resolved now.
+        pub fn parse_with_resolver(
+            s: &str,
+            resolver: Option<&dyn InterfaceResolver>,
+        ) -> Result<Address, MessageError> {
+            let invalid = |reason: &str| MessageError::InvalidAddress(format!("{}: {}", s, reason));
+            let scheme_end = s.find("://").ok_or_else(|| invalid("missing scheme"))?;
+            let (scheme, rest) = (&s[..scheme_end], &s[scheme_end + 3..]);
+            match scheme {
+                "local" => {
+                    if rest.is_empty() || rest.len() > 8 {
+                        return Err(invalid("bad local address"));
+                    }
+                    let address =
+                        u32::from_str_radix(rest, 16).map_err(|_| invalid("bad local address"))?;
+                    Ok(Address::local(address))
+                }
+                "worker" => {
+                    check_worker_name(rest.as_bytes())?;
+                    Ok(Address::named_local(rest))
+                }
+                "udp" | "tcp" => {
+                    let (ip, port) = match rest.parse::<std::net::SocketAddr>() {
+                        Ok(socket) => (socket.ip(), socket.port()),
+                        Err(_) => {
+                            let port_start =
+                                rest.rfind(':').ok_or_else(|| invalid("missing port"))?;
+                            let port = rest[port_start + 1..]
+                                .parse::<u16>()
+                                .map_err(|_| invalid("bad port"))?;
+                            let host = &rest[..port_start];
+                            let ip = resolver
+                                .and_then(|r| r.resolve(host))
+                                .ok_or_else(|| invalid("not an IP address or known interface"))?;
+                            (ip, port)
+                        }
+                    };
+                    if scheme == "udp" {
+                        Ok(Address::UdpAddress(AddressType::Udp, ip, port))
+                    } else {
+                        Ok(Address::TcpAddress(AddressType::Tcp, ip, port))
+                    }
+                }
+                _ => Err(invalid("unsupported scheme")),
+            }
+        }
+    }
+
+    /// Parses the forms described at `Address::parse_with_resolver`. Interface names are
+    /// resolved with the OS when the `interface-resolve` feature is enabled.
+    impl std::str::FromStr for Address {
+        type Err = MessageError;
+
+        #[cfg(feature = "interface-resolve")]
+        fn from_str(s: &str) -> Result<Address, MessageError> {
+            Address::parse_with_resolver(s, Some(&OsInterfaceResolver))
+        }
+
+        #[cfg(not(feature = "interface-resolve"))]
+        fn from_str(s: &str) -> Result<Address, MessageError> {
+            Address::parse_with_resolver(s, None)
+        }
+    }
+
+    impl std::fmt::Display for Route {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "[")?;
+            for (i, a) in self.addresses.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", a)?;
+            }
+            write!(f, "]")
+        }
+    }
+
+    impl Clone for Route {
+        fn clone(&self) -> Self {
+            return Route {
+                addresses: self.addresses.clone(),
+            };
+        }
+
+        fn clone_from(&mut self, source: &Self) {
+            // Reuses the existing address allocation where it can
+            self.addresses.clone_from(&source.addresses);
+        }
+    }
+
+    impl Route {
+        /// Build a route of UDP hops, one for each (IP, port) endpoint in order
+        pub fn from_udp_endpoints(endpoints: impl IntoIterator<Item = (IpAddr, u16)>) -> Route {
+            Route {
+                addresses: endpoints
+                    .into_iter()
+                    .map(|(ip, port)| Address::UdpAddress(AddressType::Udp, ip, port))
+                    .collect(),
+            }
+        }
+
+        /// Build a route of TCP hops, one for each (IP, port) endpoint in order
+        pub fn from_tcp_endpoints(endpoints: impl IntoIterator<Item = (IpAddr, u16)>) -> Route {
+            Route {
+                addresses: endpoints
+                    .into_iter()
+                    .map(|(ip, port)| Address::TcpAddress(AddressType::Tcp, ip, port))
+                    .collect(),
+            }
+        }
+
+        /// Replace the last address of the route, keeping the hops that lead to it
+        pub fn set_endpoint(&mut self, addr: Address) -> Result<(), MessageError> {
+            match self.addresses.last_mut() {
+                Some(last) => {
+                    *last = addr;
+                    Ok(())
+                }
+                None => Err(MessageError::EmptyRoute),
+            }
+        }
+
+        /// Replace the last address of the route with a local worker address
+        pub fn set_local_endpoint(&mut self, la: LocalAddress) -> Result<(), MessageError> {
+            self.set_endpoint(Address::LocalAddress(AddressType::Local, la))
+        }
+
+        /// Decode an encoded route fragment and append its hops to this route, returning the
+        /// bytes that follow the fragment. The route is left unchanged if the fragment can't
+        /// be decoded or the combined route would have more than `MAX_ROUTE_HOPS` hops.
+        pub fn extend_from_encoded<'a>(&mut self, u: &'a [u8]) -> Result<&'a [u8], MessageError> {
+            let (fragment, w) = Route::decode(u)?;
+            let hops = self.addresses.len() + fragment.addresses.len();
+            if hops > MAX_ROUTE_HOPS {
+                return Err(MessageError::RouteTooLong(hops));
+            }
+            self.addresses.extend(fragment.addresses);
+            Ok(w)
+        }
+
+        /// Rewrite every IPv4 TCP and UDP hop to its IPv4-mapped IPv6 form, `::ffff:a.b.c.d`,
+        /// including the hops behind aliases and inside tunnels. Other hops are left alone.
+        pub fn to_ipv6_mapped(&mut self) {
+            // An explicit stack, like flatten, so deep tunnel nesting can't exhaust the call
+            // stack
+            let mut stack: Vec<&mut Address> = self.addresses.iter_mut().collect();
+            while let Some(a) = stack.pop() {
+                match a {
+                    Address::UdpAddress(_, ip, _) | Address::TcpAddress(_, ip, _) => {
+                        if let IpAddr::V4(v4) = ip {
+                            *ip = IpAddr::V6(v4.to_ipv6_mapped());
+                        }
+                    }
+                    Address::Aliased(inner, _) => stack.push(inner),
+                    Address::Tunnel(route) => stack.extend(route.addresses.iter_mut()),
+                    _ => {}
+                }
+            }
+        }
+
+        /// Collapse each run of identical adjacent remote hops into a single hop. Sending to
+        /// the same remote address twice in a row only loops back through the same node, so
+        /// the route reaches the same places without the repeats. Local hops are always kept,
+        /// as each one is a separate delivery to a worker.
+        pub fn compact(&mut self) {
+            self.addresses.dedup_by(|a, b| {
+                a == b
+                    && !matches!(
+                        a.unaliased(),
+                        Address::LocalAddress(_, _) | Address::NamedLocal(_, _)
+                    )
+            });
+        }
+
+        /// The remote hops in the order to try dialing them: by the position of their
+        /// transport in `pref`, with hops whose transport isn't listed last. Hops of equal
+        /// preference keep their order in the route. Aliases are ranked by what they wrap.
+        pub fn dial_order(&self, pref: &[AddressType]) -> Vec<&Address> {
+            let rank = |a: &Address| {
+                a.unaliased()
+                    .builtin_type()
+                    .and_then(|t| pref.iter().position(|p| *p == t))
+                    .unwrap_or(pref.len())
+            };
+            let mut remote: Vec<&Address> = self
+                .addresses
+                .iter()
+                .filter(|a| {
+                    !matches!(
+                        a.unaliased(),
+                        Address::LocalAddress(_, _) | Address::NamedLocal(_, _)
+                    )
+                })
+                .collect();
+            remote.sort_by_key(|a| rank(a));
+            remote
+        }
+
+        /// Iterate over the hops for which `pred` returns true, in order
+        pub fn addresses_matching<'a>(
+            &'a self,
+            pred: impl Fn(&Address) -> bool + 'a,
+        ) -> impl Iterator<Item = &'a Address> {
+            self.addresses.iter().filter(move |a| pred(a))
+        }
+
+        /// The hops from the front of the route for as long as each is reachable from the one
+        /// before it, starting from `local`, as judged by `Address::is_reachable_from`
+        pub fn longest_reachable_prefix(&self, local: &Address) -> Route {
+            let mut from = local;
+            let mut prefix = Route { addresses: vec![] };
+            for hop in self.addresses.iter() {
+                if !hop.is_reachable_from(from) {
+                    break;
+                }
+                prefix.addresses.push(hop.clone());
+                from = hop;
+            }
+            prefix
+        }
+
+        /// Update the route by `delta`. Nothing changes if it fails: removing more hops than
+        /// the route has is `EmptyRoute`, and growing it past `MAX_ROUTE_HOPS` is
+        /// `RouteTooLong`.
+        pub fn apply_delta(&mut self, delta: &RouteDelta) -> Result<(), MessageError> {
+            match delta {
+                RouteDelta::RemoveFront(n) => {
+                    let n = *n as usize;
+                    if n > self.addresses.len() {
+                        return Err(MessageError::EmptyRoute);
+                    }
+                    self.addresses.drain(..n);
+                }
+                RouteDelta::PushFront(hops) => {
+                    let len = self.addresses.len() + hops.len();
+                    if len > MAX_ROUTE_HOPS {
+                        return Err(MessageError::RouteTooLong(len));
+                    }
+                    self.addresses.splice(0..0, hops.iter().cloned());
+                }
+                RouteDelta::Replace(route) => {
+                    if route.addresses.len() > MAX_ROUTE_HOPS {
+                        return Err(MessageError::RouteTooLong(route.addresses.len()));
+                    }
+                    self.clone_from(route)
+                }
+            }
+            Ok(())
+        }
+
+        /// Take the route apart into its first hop and a route of the hops after it, or None
+        /// if the route is empty
+        pub fn split_first(mut self) -> Option<(Address, Route)> {
+            if self.addresses.is_empty() {
+                return None;
+            }
+            let head = self.addresses.remove(0);
+            Some((head, self))
+        }
+
+        /// Keep only the hops for which `f` returns true, in order, like `Vec::retain`
+        pub fn retain<F: FnMut(&Address) -> bool>(&mut self, f: F) {
+            self.addresses.retain(f);
+        }
+
+        /// Whether every hop is a local worker. An empty route counts as local only.
+        pub fn is_local_only(&self) -> bool {
+            !self.has_remote()
+        }
+
+        /// Whether no hop is a local worker. An empty route counts as remote only.
+        pub fn is_remote_only(&self) -> bool {
+            self.addresses.iter().all(|a| {
+                !matches!(
+                    a.unaliased(),
+                    Address::LocalAddress(_, _) | Address::NamedLocal(_, _)
+                )
+            })
+        }
+
+        /// Whether any hop leaves the node. Tunnel and custom hops count as remote.
+        pub fn has_remote(&self) -> bool {
+            self.addresses.iter().any(|a| {
+                !matches!(
+                    a.unaliased(),
+                    Address::LocalAddress(_, _) | Address::NamedLocal(_, _)
+                )
+            })
+        }
+
+        // Fail with RouteTooLong if adding a hop would take the route past MAX_ROUTE_HOPS
+        fn check_room_for_hop(&self) -> Result<(), MessageError> {
+            let hops = self.addresses.len();
+            if hops >= MAX_ROUTE_HOPS {
+                return Err(MessageError::RouteTooLong(hops + 1));
+            }
+            Ok(())
+        }
+
+        /// Whether both routes hold the same addresses, each the same number of times, in any
+        /// order. Unlike `==`, this treats the routes as multisets and ignores hop order.
+        pub fn same_set(&self, other: &Route) -> bool {
+            if self.addresses.len() != other.addresses.len() {
+                return false;
+            }
+            let mut counts: HashMap<&Address, usize> = HashMap::new();
+            for a in self.addresses.iter() {
+                *counts.entry(a).or_insert(0) += 1;
+            }
+            for a in other.addresses.iter() {
+                match counts.get_mut(a) {
+                    Some(n) if *n > 0 => *n -= 1,
+                    _ => return false,
+                }
+            }
+            true
+        }
+
+        /// How many bytes `encode` writes for this route
+        pub fn encoded_len(&self) -> usize {
+            varint_u64_len(self.addresses.len() as u64)
+                + self
+                    .addresses
+                    .iter()
+                    .map(|a| a.encoded_len())
+                    .sum::<usize>()
+        }
+
+        /// A hash of the route's canonical encoding, so equal routes hash equal however they
+        /// were built. Like `Message::body_hash` it's FNV-1a, so the value is the same across
+        /// runs and hosts. Routes that can't be encoded, such as ones holding custom
+        /// addresses, fail with the encoding error.
+        pub fn encoded_hash(&self) -> Result<u64, MessageError> {
+            let mut encoded = Vec::with_capacity(self.encoded_len());
+            Route::encode(self, &mut encoded)?;
+            Ok(fnv1a(&encoded))
+        }
+
+        /// Splice the hops of any tunneled routes inline, producing a route with no tunnel hops
+        pub fn flatten(&self) -> Route {
+            // Walk with an explicit stack rather than recursing, so however deeply an
+            // in-memory route nests its tunnels it can't exhaust the call stack
+            let mut addresses = vec![];
+            let mut stack = vec![self.addresses.iter()];
+            while let Some(hops) = stack.last_mut() {
+                match hops.next() {
+                    Some(Address::Tunnel(inner)) => stack.push(inner.addresses.iter()),
+                    Some(a) => addresses.push(a.clone()),
+                    None => {
+                        stack.pop();
+                    }
+                }
+            }
+            Route { addresses }
+        }
+
+        /// Encode a route using an alternative wire format
+        pub fn encode_with_format(
+            route: &Route,
+            u: &mut Vec<u8>,
+            format: WireFormat,
+        ) -> Result<(), MessageError> {
+            Route::encode_nested(route, u, format, None, 0)
+        }
+
+        /// Decode a route encoded with an alternative wire format
+        pub fn decode_with_format(
+            encoded: &[u8],
+            format: WireFormat,
+        ) -> Result<(Route, &[u8]), MessageError> {
+            Route::decode_nested(encoded, &DecodeConfig::default(), format, 0)
+        }
+
+        fn encode_nested(
+            route: &Route,
+            u: &mut Vec<u8>,
+            format: WireFormat,
+            registry: Option<&AddressCodecRegistry>,
+            depth: usize,
+        ) -> Result<(), MessageError> {
+            // The address count is a variable-length u64, though no more than MAX_ROUTE_HOPS
+            if route.addresses.len() > MAX_ROUTE_HOPS {
+                return Err(MessageError::RouteTooLong(route.addresses.len()));
+            }
+            u64::encode(&(route.addresses.len() as u64), u)?;
+            for i in 0..route.addresses.len() {
+                Address::encode_nested(&route.addresses[i], u, format, registry, depth)?;
+            }
+            Ok(())
+        }
+
+        /// Decode a route, applying the limits in `config`. Decoding uses a constant amount of
+        /// stack however many hops the route has, plus a bounded amount per level of tunnel
+        /// nesting up to `config.max_depth`.
+        pub fn decode_with_config<'a>(
+            encoded: &'a [u8],
+            config: &DecodeConfig,
+        ) -> Result<(Route, &'a [u8]), MessageError> {
+            Route::decode_nested(encoded, config, WireFormat::default(), 0)
+        }
+
+        fn decode_nested<'a>(
+            encoded: &'a [u8],
+            config: &DecodeConfig,
+            format: WireFormat,
+            depth: usize,
+        ) -> Result<(Route, &'a [u8]), MessageError> {
+            let mut route = Route { addresses: vec![] };
+            let next_address = route.decode_addresses(encoded, config, format, depth)?;
+            Ok((route, next_address))
+        }
+
+        // Decode an encoded route, appending its addresses to this route. Hops are decoded
+        // in a loop, so the stack this needs doesn't grow with the hop count; only tunnel
+        // hops recurse, and no deeper than config.max_depth.
+        fn decode_addresses<'a>(
+            &mut self,
+            encoded: &'a [u8],
+            config: &DecodeConfig,
+            format: WireFormat,
+            depth: usize,
+        ) -> Result<&'a [u8], MessageError> {
+            // Empty input fails here with BufferTooShort rather than reading as an empty route
+            let (count, mut next_address) = u64::decode(encoded)?;
+            if count == 0 {
+                // An empty route is its zero count alone
+                return Ok(next_address);
+            }
+            check_route_count(count, next_address, config.registry.as_deref())?;
+            for _ in 0..count {
+                let (a, x) = Address::decode_nested(next_address, config, format, depth)?;
+                self.addresses.push(a);
+                next_address = x;
+            }
+            Ok(next_address)
+        }
+    }
+
+    impl Route {
+        /// Advance past an encoded route without decoding its addresses, returning the bytes
+        /// that follow it. This lets a proxy reach the body of a frame it only forwards.
+        pub fn skip(encoded: &[u8]) -> Result<&[u8], MessageError> {
+            skip_route(encoded, WireFormat::default(), None)
+        }
+
+        /// Advance past an encoded route as `skip` does, handing address types the crate
+        /// doesn't know to `registry` to find where they end
+        pub fn skip_with_registry<'a>(
+            encoded: &'a [u8],
+            registry: &AddressCodecRegistry,
+        ) -> Result<&'a [u8], MessageError> {
+            skip_route(encoded, WireFormat::default(), Some(registry))
+        }
+
+        /// Encode the route on its own, for storing it apart from any message, such as a
+        /// reply path
+        pub fn encode_to_vec(&self) -> Result<Vec<u8>, MessageError> {
+            let mut u = Vec::with_capacity(self.encoded_len());
+            Route::encode(self, &mut u)?;
+            Ok(u)
+        }
+
+        /// Decode a route encoded on its own by `encode_to_vec`. Unlike `decode`, nothing may
+        /// follow it.
+        pub fn from_bytes(u: &[u8]) -> Result<Route, MessageError> {
+            match Route::decode(u)? {
+                (route, []) => Ok(route),
+                (_, rest) => Err(MessageError::TrailingBytes(rest.len())),
+            }
+        }
+
+        /// Decode a route from a source that yields bytes lazily, such as a ring buffer.
+        /// Bytes are pulled only as the slice decoder reports it needs them, so nothing past
+        /// the end of the route is consumed. An iterator that runs dry mid-route gives
+        /// `BufferTooShort`.
+        pub fn decode_from_iter<I: Iterator<Item = u8>>(iter: I) -> Result<Route, MessageError> {
+            let mut iter = iter;
+            let mut buffer = vec![];
+            loop {
+                let missing = match Route::decode(&buffer) {
+                    Ok((route, _)) => return Ok(route),
+                    Err(MessageError::BufferTooShort { needed, got }) => {
+                        needed.saturating_sub(got).max(1)
+                    }
+                    // The addresses take at least a byte each, so at least this many more
+                    // bytes belong to the route
+                    Err(MessageError::RouteTruncated { count, got }) => usize::try_from(count)
+                        .unwrap_or(usize::MAX)
+                        .saturating_sub(got)
+                        .max(1),
+                    Err(e) => return Err(e),
+                };
+                let have = buffer.len();
+                buffer.extend(iter.by_ref().take(missing));
+                if buffer.len() - have < missing {
+                    return Err(MessageError::BufferTooShort {
+                        needed: have + missing,
+                        got: buffer.len(),
+                    });
+                }
+            }
+        }
+    }
+
+    // A route may have no more than MAX_ROUTE_HOPS addresses. Every address takes at least
+    // `min_address_len` bytes, so a route declaring more addresses than the bytes left could
+    // hold is rejected before any of them is parsed.
+    fn check_route_count(
+        count: u64,
+        rest: &[u8],
+        registry: Option<&AddressCodecRegistry>,
+    ) -> Result<(), MessageError> {
+        if count > MAX_ROUTE_HOPS as u64 {
+            return Err(MessageError::RouteTooLong(
+                usize::try_from(count).unwrap_or(usize::MAX),
+            ));
+        }
+        // No overflow, as the count is at most MAX_ROUTE_HOPS
+        if count * min_address_len(registry) as u64 > rest.len() as u64 {
+            return Err(MessageError::RouteTruncated {
+                count,
+                got: rest.len(),
+            });
+        }
+        Ok(())
+    }
+
+    // The fewest bytes an address can take. A custom address, which only a registry can
+    // decode, may be its type byte alone.
+    fn min_address_len(registry: Option<&AddressCodecRegistry>) -> usize {
+        if registry.is_some() {
+            return 1;
+        }
+        [
+            AddressType::Local,
+            AddressType::Tcp,
+            AddressType::Udp,
+            AddressType::Tunnel,
+            AddressType::NamedLocal,
+            AddressType::Aliased,
+        ]
+        .iter()
+        .map(AddressType::min_encoded_len)
+        .min()
+        .unwrap_or(1)
+    }
+
+    fn skip_route<'a>(
+        encoded: &'a [u8],
+        format: WireFormat,
+        registry: Option<&AddressCodecRegistry>,
+    ) -> Result<&'a [u8], MessageError> {
+        let (count, mut rest) = u64::decode(encoded)?;
+        check_route_count(count, rest, registry)?;
+        for _ in 0..count {
+            rest = skip_address(rest, format, registry)?;
+        }
+        Ok(rest)
+    }
+
+    fn skip_address<'a>(
+        u: &'a [u8],
+        format: WireFormat,
+        registry: Option<&AddressCodecRegistry>,
+    ) -> Result<&'a [u8], MessageError> {
+        // Aliases wrap an address and are followed by their names, so count the aliases in
+        // front, skip the address they wrap, then skip a name for each
+        let mut u = u;
+        let mut aliases = 0;
+        while u.first() == Some(&(AddressType::Aliased as u8)) {
+            aliases += 1;
+            u = &u[1..];
+        }
+        let mut rest = skip_unaliased_address(u, format, registry)?;
+        for _ in 0..aliases {
+            rest = decode_blob(rest, MAX_BLOB_LEN)?.1;
+        }
+        Ok(rest)
+    }
+
+    fn skip_unaliased_address<'a>(
+        u: &'a [u8],
+        format: WireFormat,
+        registry: Option<&AddressCodecRegistry>,
+    ) -> Result<&'a [u8], MessageError> {
+        let (t, rest) = split_at_checked(u, 1)?;
+        let address_type = match AddressType::try_from(t[0]) {
+            Ok(address_type) => address_type,
+            Err(e) => {
+                return match registry {
+                    Some(registry) => Ok(registry.decode(t[0], rest)?.1),
+                    None => Err(e),
+                }
+            }
+        };
+        let len = match address_type {
+            AddressType::Local => 4,
+            AddressType::Udp | AddressType::Tcp => {
+                let (host, _) = split_at_checked(rest, 1)?;
+                let ip_len = match HostAddressType::try_from(host[0])? {
+                    HostAddressType::Ipv4 => 4,
+                    HostAddressType::Ipv6 => 16,
+                };
+                if format.varint_port_encoding() {
+                    let (_, port) = split_at_checked(rest, 1 + ip_len)?;
+                    return Ok(u16::decode(port)?.1);
+                }
+                1 + ip_len + 2
+            }
+            AddressType::Tunnel => return Ok(decode_blob(rest, MAX_BLOB_LEN)?.1),
+            AddressType::NamedLocal => return Ok(decode_blob(rest, MAX_WORKER_NAME_LEN)?.1),
+            AddressType::Aliased => unreachable!("aliases are skipped by skip_address"),
+        };
+        Ok(split_at_checked(rest, len)?.1)
+    }
+
+    fn split_at_checked(u: &[u8], n: usize) -> Result<(&[u8], &[u8]), MessageError> {
+        if u.len() < n {
+            return Err(MessageError::BufferTooShort {
+                needed: n,
+                got: u.len(),
+            });
+        }
+        Ok(u.split_at(n))
+    }
+
+    /// The full routes to destination workers a node knows of, so a message can be sent with
+    /// just the worker's address and completed before it leaves
+    #[derive(Clone, Debug, Default)]
+    pub struct RouteTable {
+        routes: HashMap<Address, Route>,
+    }
+
+    impl RouteTable {
+        pub fn new() -> RouteTable {
+            RouteTable::default()
+        }
+
+        /// Store the route to `dest`, returning the route it replaces, if any
+        pub fn insert(&mut self, dest: Address, route: Route) -> Option<Route> {
+            self.routes.insert(dest, route)
+        }
+
+        /// The stored route to `dest`
+        pub fn lookup(&self, dest: &Address) -> Option<&Route> {
+            self.routes.get(dest)
+        }
+
+        /// Forget the route to `dest`, returning it
+        pub fn remove(&mut self, dest: &Address) -> Option<Route> {
+            self.routes.remove(dest)
+        }
+
+        /// If the message's onward route is a single local worker address with a stored
+        /// route, replace it with that route. Returns whether the route was replaced.
+        pub fn resolve(&self, msg: &mut Message) -> bool {
+            let dest = match msg.onward_route.addresses.as_slice() {
+                [dest @ Address::LocalAddress(..)] | [dest @ Address::NamedLocal(..)] => dest,
+                _ => return false,
+            };
+            match self.routes.get(dest) {
+                Some(route) => {
+                    msg.onward_route.clone_from(route);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    /// A change to a route, sent in place of the whole route when only a hop or two changes
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum RouteDelta {
+        /// Remove this many hops from the front
+        RemoveFront(u8),
+        /// Put these hops in front, in order
+        PushFront(Vec<Address>),
+        /// Swap in a whole new route
+        Replace(Route),
+    }
+
+    // A delta is a tag byte followed by the hop count to remove or the hops as a route
+    impl Codec for RouteDelta {
+        type Inner = RouteDelta;
+        fn encode(delta: &RouteDelta, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            // Don't leave a partially encoded delta behind on failure
+            let start = u.len();
+            let result = match delta {
+                RouteDelta::RemoveFront(n) => {
+                    u.push(0);
+                    u.push(*n);
+                    Ok(())
+                }
+                RouteDelta::PushFront(hops) => {
+                    u.push(1);
+                    // Written as a route is, without building one from the hops
+                    if hops.len() > MAX_ROUTE_HOPS {
+                        Err(MessageError::RouteTooLong(hops.len()))
+                    } else {
+                        u64::encode(&(hops.len() as u64), u)
+                            .and_then(|()| hops.iter().try_for_each(|a| Address::encode(a, u)))
+                    }
+                }
+                RouteDelta::Replace(route) => {
+                    u.push(2);
+                    Route::encode(route, u)
+                }
+            };
+            if result.is_err() {
+                u.truncate(start);
+            }
+            result
+        }
+        fn decode(u: &[u8]) -> Result<(RouteDelta, &[u8]), MessageError> {
+            let (tag, w) = split_at_checked(u, 1)?;
+            match tag[0] {
+                0 => {
+                    let (n, w) = split_at_checked(w, 1)?;
+                    Ok((RouteDelta::RemoveFront(n[0]), w))
+                }
+                1 => {
+                    let (route, w) = Route::decode(w)?;
+                    Ok((RouteDelta::PushFront(route.addresses), w))
+                }
+                2 => {
+                    let (route, w) = Route::decode(w)?;
+                    Ok((RouteDelta::Replace(route), w))
+                }
+                t => Err(MessageError::UnknownRouteDelta(t)),
+            }
+        }
+    }
+
+    /// Builds a route one hop at a time, rejecting a hop as soon as it is added if it is
+    /// invalid or would take the route past `MAX_ROUTE_HOPS`
+    #[derive(Clone, Debug, Default)]
+    pub struct RouteBuilder {
+        route: Route,
+    }
+
+    impl RouteBuilder {
+        pub fn new() -> RouteBuilder {
+            RouteBuilder::default()
+        }
+
+        /// Add a hop to the end of the route. IP hops need a port other than 0 and a
+        /// specified IP. The hops inside aliases and tunnels are checked too.
+        pub fn hop(mut self, addr: Address) -> Result<RouteBuilder, MessageError> {
+            self.route.check_room_for_hop()?;
+            check_hop(&addr)?;
+            self.route.addresses.push(addr);
+            Ok(self)
+        }
+
+        pub fn build(self) -> Route {
+            self.route
+        }
+    }
+
+    fn check_hop(addr: &Address) -> Result<(), MessageError> {
+        match addr {
+            Address::UdpAddress(_, ip, port) | Address::TcpAddress(_, ip, port) => {
+                if *port == 0 {
+                    return Err(MessageError::InvalidAddress(format!("{}: port 0", addr)));
+                }
+                if ip.is_unspecified() {
+                    return Err(MessageError::InvalidAddress(format!(
+                        "{}: unspecified IP",
+                        addr
+                    )));
+                }
+            }
+            Address::Aliased(inner, _) => check_hop(inner)?,
+            Address::Tunnel(route) => {
+                for a in route.addresses.iter() {
+                    check_hop(a)?;
+                }
+            }
+            Address::LocalAddress(_, _) | Address::NamedLocal(_, _) | Address::Custom(_, _) => {}
+        }
+        Ok(())
+    }
+
+    /// Decides which addresses a node is willing to handle, for example to restrict which
+    /// transports or hosts it will relay to
+    pub trait AddressPolicy {
+        fn allow(&self, address: &Address) -> bool;
+    }
+
+    /// Allows only addresses that stay on this host: local workers and loopback IPs
+    pub struct LoopbackOnlyPolicy;
+
+    impl AddressPolicy for LoopbackOnlyPolicy {
+        fn allow(&self, address: &Address) -> bool {
+            match address {
+                Address::LocalAddress(_, _) | Address::NamedLocal(_, _) => true,
+                Address::TcpAddress(_, ip, _) | Address::UdpAddress(_, ip, _) => ip.is_loopback(),
+                Address::Tunnel(route) => route.flatten().addresses.iter().all(|a| self.allow(a)),
+                Address::Aliased(inner, _) => self.allow(inner),
+                Address::Custom(_, _) => false,
+            }
+        }
+    }
+
+    /// Weighs hops for path selection, for example by expected latency or transport preference
+    pub trait CostModel {
+        fn hop_cost(&self, address: &Address) -> u32;
+    }
+
+    /// Charges nothing for local workers and 1 for every other hop, so a route's cost is the
+    /// number of hops that leave the node
+    pub struct RemoteHopCostModel;
+
+    impl CostModel for RemoteHopCostModel {
+        fn hop_cost(&self, address: &Address) -> u32 {
+            match address.unaliased() {
+                Address::LocalAddress(_, _) | Address::NamedLocal(_, _) => 0,
+                _ => 1,
+            }
+        }
+    }
+
+    impl Route {
+        /// Decode a route, rejecting it with `PolicyViolation` if the policy disallows any of
+        /// its addresses. The hops inside tunnel hops are checked too.
+        pub fn decode_with_policy<'a>(
+            encoded: &'a [u8],
+            policy: &dyn AddressPolicy,
+        ) -> Result<(Route, &'a [u8]), MessageError> {
+            let (route, w) = Route::decode(encoded)?;
+            for a in route.flatten().addresses.iter() {
+                if !policy.allow(a) {
+                    return Err(MessageError::PolicyViolation(a.to_string()));
+                }
+            }
+            Ok((route, w))
+        }
+    }
+
+    impl Codec for Route {
+        type Inner = Route;
+        fn encode(route: &Route, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            Route::encode_nested(route, u, WireFormat::default(), None, 0)
+        }
+        fn decode(encoded: &[u8]) -> Result<(Route, &[u8]), MessageError> {
+            Route::decode_with_config(encoded, &DecodeConfig::default())
+        }
+    }
+
+    /// Encode an onward and a return route together, writing the leading hops they have in
+    /// common only once. The result is three encoded routes: the shared prefix, then what
+    /// remains of the onward route, then what remains of the return route. This is an opt-in
+    /// alternative to encoding each route separately; decode it with `decode_routes_shared`.
+    pub fn encode_routes_shared(
+        onward: &Route,
+        return_route: &Route,
+        out: &mut Vec<u8>,
+    ) -> Result<(), MessageError> {
+        let shared = onward
+            .addresses
+            .iter()
+            .zip(return_route.addresses.iter())
+            .take_while(|(o, r)| o == r)
+            .count();
+        let start = out.len();
+        let encoded = Route::encode(
+            &Route {
+                addresses: onward.addresses[..shared].to_vec(),
+            },
+            out,
+        )
+        .and_then(|()| {
+            Route::encode(
+                &Route {
+                    addresses: onward.addresses[shared..].to_vec(),
+                },
+                out,
+            )
+        })
+        .and_then(|()| {
+            Route::encode(
+                &Route {
+                    addresses: return_route.addresses[shared..].to_vec(),
+                },
+                out,
+            )
+        });
+        if encoded.is_err() {
+            out.truncate(start);
+        }
+        encoded
+    }
+
+    /// Decode an onward and a return route written by `encode_routes_shared`
+    pub fn decode_routes_shared(u: &[u8]) -> Result<(Route, Route, &[u8]), MessageError> {
+        let (shared, w) = Route::decode(u)?;
+        let (onward_rest, w) = Route::decode(w)?;
+        let (return_rest, w) = Route::decode(w)?;
+        let mut onward = shared.clone();
+        onward.addresses.extend(onward_rest.addresses);
+        let mut return_route = shared;
+        return_route.addresses.extend(return_rest.addresses);
+        Ok((onward, return_route, w))
+    }
+
+    // ToDo: Implement PartialEq, Eq, Copy, Clone
+
+    // u16's are encoded as variable-length.
+    // - If the value is < 0x80, it is encoded as-is, in one byte
+    // - If the value is <= 0x80, the highest-order of the low-order byte is moved to the
+    //   lowest-order bit in the high-order byte, and the high-order byte is shifted left by one to
+    //   make room.
+    // Shifting the high-order byte drops its top bit, so only values below 0x8000 fit.
+    impl Codec for u16 {
+        type Inner = u16;
+        fn encode(ul2: &u16, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            if ul2 >= &mut 0x8000 {
+                return Err(MessageError::ValueTooLarge);
+            }
+            let mut bytes = ul2.to_le_bytes();
+
+            if ul2 < &mut 0x80 {
+                u.push(bytes[0])
+            } else {
+                bytes[1] <<= 0x01;
+                if 0 != (bytes[0] & 0x80) {
+                    bytes[1] |= 0x01;
+                }
+                bytes[0] |= 0x80;
+                u.push(bytes[0]);
+                u.push(bytes[1])
+            }
+            Ok(())
+        }
+
+        fn decode(u: &[u8]) -> Result<(u16, &[u8]), MessageError> {
+            let needed = match u.first() {
+                Some(b) if b & 0x80 == 0x80 => 2,
+                _ => 1,
+            };
+            if u.len() < needed {
+                return Err(MessageError::BufferTooShort {
+                    needed,
+                    got: u.len(),
+                });
+            }
+            // The first byte holds the low 7 bits and the second, if any, the 8 bits above
+            // them. Combining them as u16s can't overflow: the largest is 0x7f | 0xff << 7.
+            let low = u16::from(u[0] & 0x7f);
+            if needed == 1 {
+                return Ok((low, &u[1..]));
+            }
+            Ok((low | u16::from(u[1]) << 7, &u[2..]))
+        }
+    }
+
+    // Fixed width and little-endian, like local addresses
+    impl Codec for u32 {
+        type Inner = u32;
+        fn encode(value: &u32, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            u.extend_from_slice(&value.to_le_bytes());
+            Ok(())
+        }
+        fn decode(u: &[u8]) -> Result<(u32, &[u8]), MessageError> {
+            let (b, w) = split_at_checked(u, 4)?;
+            Ok((u32::from_le_bytes([b[0], b[1], b[2], b[3]]), w))
+        }
+    }
+
+    /// Encodes a u16 as two little-endian bytes, for the `fixed_u16` wire format. Unlike the
+    /// variable-length `u16` codec it can encode every value.
+    pub struct FixedU16;
+
+    impl Codec for FixedU16 {
+        type Inner = u16;
+        fn encode(value: &u16, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            u.extend_from_slice(&value.to_le_bytes());
+            Ok(())
+        }
+        fn decode(u: &[u8]) -> Result<(u16, &[u8]), MessageError> {
+            let (b, w) = split_at_checked(u, 2)?;
+            Ok((u16::from_le_bytes([b[0], b[1]]), w))
+        }
+    }
+
+    // Unsigned LEB128: seven bits per byte, least significant group first, with the top bit
+    // set on every byte but the last. Values below 0x8000 encode the same as with the u16
+    // codec above.
+    impl Codec for u64 {
+        type Inner = u64;
+        fn encode(value: &u64, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            let mut value = *value;
+            loop {
+                let byte = (value & 0x7f) as u8;
+                value >>= 7;
+                if value == 0 {
+                    u.push(byte);
+                    return Ok(());
+                }
+                u.push(byte | 0x80);
+            }
+        }
+
+        fn decode(u: &[u8]) -> Result<(u64, &[u8]), MessageError> {
+            let mut value: u64 = 0;
+            for (i, byte) in u.iter().enumerate() {
+                let bits = (byte & 0x7f) as u64;
+                // The tenth byte may only hold the single bit left of a u64
+                if i > 9 || (i == 9 && bits > 1) {
+                    return Err(MessageError::ValueTooLarge);
+                }
+                value |= bits << (7 * i);
+                if byte & 0x80 == 0 {
+                    return Ok((value, &u[i + 1..]));
+                }
+            }
+            Err(MessageError::BufferTooShort {
+                needed: u.len() + 1,
+                got: u.len(),
+            })
+        }
+    }
+
+    // The most bytes the u64 codec uses for a value
+    const MAX_VARINT_U64_LEN: usize = 10;
+
+    // The version, both flags bytes, the TTL, the priority and the optional fields
+    const MAX_HEADER_LEN: usize =
+        2 + 2 + 1 + 1 + (1 + MAX_VARINT_U64_LEN) + (1 + 4) + (1 + MAX_VARINT_U64_LEN);
+
+    // A UDP or TCP address with an IPv6 host: the address type, the host type, the host and
+    // the port. Local addresses are smaller.
+    const MAX_FIXED_ADDRESS_LEN: usize = 1 + 1 + 16 + 2;
+
+    // How many bytes the u64 codec uses for a value
+    fn varint_u64_len(mut value: u64) -> usize {
+        let mut len = 1;
+        while value >= 0x80 {
+            value >>= 7;
+            len += 1;
+        }
+        len
+    }
+
+    /// The longest blob `Blob::decode` accepts
+    pub const MAX_BLOB_LEN: usize = 0xffff;
+
+    /// A byte string encoded with a variable-length u64 length prefix
+    #[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct Blob(pub Vec<u8>);
+
+    impl Blob {
+        /// Decode a blob, failing with `ValueTooLarge` if it is longer than `max_len`
+        pub fn decode_with_max(u: &[u8], max_len: usize) -> Result<(Blob, &[u8]), MessageError> {
+            let (bytes, w) = decode_blob(u, max_len)?;
+            Ok((Blob(bytes.to_vec()), w))
+        }
+    }
+
+    impl Codec for Blob {
+        type Inner = Blob;
+        fn encode(blob: &Blob, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            encode_blob(&blob.0, u)
+        }
+        fn decode(u: &[u8]) -> Result<(Blob, &[u8]), MessageError> {
+            Blob::decode_with_max(u, MAX_BLOB_LEN)
+        }
+    }
+
+    /// Encode an optional value as a presence byte, 0 for `None` and 1 for `Some`, followed
+    /// by the value if there is one, as the fields `EXT_FLAG_OPTIONAL_FIELDS` marks are
+    pub(crate) fn encode_option<C: Codec>(
+        value: &Option<C::Inner>,
+        u: &mut Vec<u8>,
+    ) -> Result<(), MessageError> {
+        match value {
+            Some(v) => {
+                u.push(1);
+                C::encode(v, u)
+            }
+            None => {
+                u.push(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Decode an optional value written by `encode_option`
+    pub(crate) fn decode_option<C: Codec>(
+        u: &[u8],
+    ) -> Result<(Option<C::Inner>, &[u8]), MessageError> {
+        match u.split_first() {
+            Some((0, w)) => Ok((None, w)),
+            Some((1, w)) => {
+                let (v, w) = C::decode(w)?;
+                Ok((Some(v), w))
+            }
+            Some((tag, _)) => Err(MessageError::InvalidPresenceByte(*tag)),
+            None => Err(MessageError::BufferTooShort { needed: 1, got: 0 }),
+        }
+    }
+
+    fn encode_blob(bytes: &[u8], u: &mut Vec<u8>) -> Result<(), MessageError> {
+        u64::encode(&(bytes.len() as u64), u)?;
+        u.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    // Decode a blob without copying it, returning its bytes and what follows
+    fn decode_blob(u: &[u8], max_len: usize) -> Result<(&[u8], &[u8]), MessageError> {
+        let (len, w) = u64::decode(u)?;
+        if len > max_len as u64 {
+            return Err(MessageError::ValueTooLarge);
+        }
+        split_at_checked(w, len as usize)
+    }
+
+    fn blob_encoded_len(len: usize) -> usize {
+        varint_u64_len(len as u64) + len
+    }
+
+    #[derive(Debug)]
+    #[repr(C)]
+    pub struct WireProtocolVersion {
+        pub v: u16,
+    }
+
+    impl Default for WireProtocolVersion {
+        fn default() -> WireProtocolVersion {
+            WireProtocolVersion { v: 1 }
+        }
+    }
+
+    /// Exchanged by peers to agree on a wire protocol version. Each side advertises the
+    /// inclusive range of versions it supports.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    pub struct Handshake {
+        pub min_version: u16,
+        pub max_version: u16,
+    }
+
+    impl Handshake {
+        /// The highest version both sides support, if their ranges overlap
+        pub fn negotiate(local: &Handshake, remote: &Handshake) -> Option<u16> {
+            let min = std::cmp::max(local.min_version, remote.min_version);
+            let max = std::cmp::min(local.max_version, remote.max_version);
+            if min <= max {
+                Some(max)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl Codec for Handshake {
+        type Inner = Handshake;
+        fn encode(h: &Handshake, u: &mut Vec<u8>) -> Result<(), MessageError> {
+            u16::encode(&h.min_version, u)?;
+            u16::encode(&h.max_version, u)
+        }
+        fn decode(u: &[u8]) -> Result<(Handshake, &[u8]), MessageError> {
+            let (min_version, w) = u16::decode(u)?;
+            let (max_version, w) = u16::decode(w)?;
+            Ok((
+                Handshake {
+                    min_version,
+                    max_version,
+                },
+                w,
+            ))
+        }
+    }
+
+    // std::io::Read & std::io::Write trait implementation
+    impl std::io::Read for Message {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
+            if buf.len() < self.message_body.len() {
+                return Err(MessageError::BufferTooShort {
+                    needed: self.message_body.len(),
+                    got: buf.len(),
+                }
+                .into());
+            }
+            for i in 0..self.message_body.len() {
+                buf[i] = self.message_body[i];
+            }
+            Ok(self.message_body.len())
+        }
+    }
+    impl std::io::Write for Message {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+            if !self.message_body.is_empty() {
+                return Err(std::io::Error::new(ErrorKind::Other, "no message body"));
+            }
+            for i in 0..buf.len() {
+                self.message_body.push(buf[i]);
+            }
+            Ok(self.message_body.len())
+        }
+        fn flush(&mut self) -> Result<(), std::io::Error> {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::*;
+    use std::convert::TryFrom;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // Encode `value` as `$t` and check the bytes, then decode them and check the value
+    // survives with nothing left over
+    macro_rules! assert_codec_roundtrip {
+        // Messages have no `PartialEq`, so they are compared with `diff`. Their body is the
+        // rest of the frame, which `decode` returns as well.
+        (Message, $value:expr, $expected:expr) => {{
+            let value = $value;
+            let mut v: Vec<u8> = vec![];
+            Message::encode(&value, &mut v).unwrap();
+            let expected: Vec<u8> = $expected;
+            assert_bytes_eq(&v, &expected);
+            let (decoded, rest) = Message::decode(&v).unwrap();
+            assert_eq!(decoded.diff(&value), None);
+            assert_eq!(rest, &value.message_body[..]);
+        }};
+        ($t:ty, $value:expr, $expected:expr) => {{
+            let value = $value;
+            let mut v: Vec<u8> = vec![];
+            <$t as Codec>::encode(&value, &mut v).unwrap();
+            let expected: Vec<u8> = $expected;
+            assert_bytes_eq(&v, &expected);
+            let (decoded, rest) = <$t as Codec>::decode(&v).unwrap();
+            assert_eq!(decoded, value);
+            assert_eq!(rest.len(), 0);
+        }};
+    }
+
+    // Compare encoded bytes, panicking with the first offset they differ at and the bytes
+    // around it in hex, rather than two long arrays
+    fn assert_bytes_eq(actual: &[u8], expected: &[u8]) {
+        const CONTEXT: usize = 8;
+        let offset = match actual.iter().zip(expected.iter()).position(|(a, e)| a != e) {
+            Some(offset) => offset,
+            None if actual.len() == expected.len() => return,
+            None => actual.len().min(expected.len()),
+        };
+        let window = |u: &[u8]| {
+            let start = offset.saturating_sub(CONTEXT);
+            let end = (offset + CONTEXT + 1).min(u.len());
+            let mut hex = String::new();
+            for (i, b) in u.iter().enumerate().take(end).skip(start) {
+                if i == offset {
+                    hex.push_str(&format!("[{:02x}] ", b));
+                } else {
+                    hex.push_str(&format!("{:02x} ", b));
+                }
+            }
+            hex.trim_end().to_string()
+        };
+        panic!(
+            "bytes differ at offset {} (lengths {} and {})\n  actual: {}\nexpected: {}",
+            offset,
+            actual.len(),
+            expected.len(),
+            window(actual),
+            window(expected)
+        );
+    }
+
+    #[test]
+    fn assert_bytes_eq_reports_offset() {
+        assert_bytes_eq(&[1, 2, 3], &[1, 2, 3]);
+
+        let message = |actual: Vec<u8>, expected: Vec<u8>| {
+            let err = std::panic::catch_unwind(|| assert_bytes_eq(&actual, &expected))
+                .expect_err("the bytes differ");
+            err.downcast_ref::<String>().unwrap().clone()
+        };
+        let m = message(
+            (0..20).collect(),
+            (0..20).map(|b| b ^ (b == 12) as u8).collect(),
+        );
+        assert!(m.starts_with("bytes differ at offset 12 (lengths 20 and 20)"));
+        assert!(m.contains("  actual: 04 05 06 07 08 09 0a 0b [0c] 0d 0e 0f 10 11 12 13"));
+        assert!(m.contains("expected: 04 05 06 07 08 09 0a 0b [0d] 0d 0e 0f 10 11 12 13"));
+
+        let m = message(vec![1, 2], vec![1, 2, 3]);
+        assert!(m.starts_with("bytes differ at offset 2 (lengths 2 and 3)"));
+        assert!(m.ends_with("expected: 01 02 [03]"));
+    }
+
+    #[test]
+    fn local_address_codec() {
+        assert_codec_roundtrip!(
+            LocalAddress,
+            LocalAddress {
+                address: 0x00010203,
+            },
+            vec![3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn ip4_address_codec() {
+        assert_codec_roundtrip!(
+            IpAddr,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            vec![0, 127, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn ip6_address_codec() {
+        assert_codec_roundtrip!(
+            IpAddr,
+            IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            vec![1, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn address_codec() {
+        assert_codec_roundtrip!(
+            Address,
+            Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                0x8080,
+            ),
+            vec![2, 0, 127, 0, 0, 1, 0x80, 0x80]
+        );
+        assert_codec_roundtrip!(
+            Address,
+            Address::LocalAddress(
+                AddressType::Local,
+                LocalAddress {
+                    address: 0x00010203,
+                },
+            ),
+            vec![0, 3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn route_codec() {
+        let mut route: Route = Route { addresses: vec![] };
+        route.addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            0x8080,
+        ));
+        route.addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        ));
+        route.addresses.push(Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x00010203,
+            },
+        ));
+        assert_codec_roundtrip!(
+            Route,
+            route,
+            vec![3, 2, 0, 127, 0, 0, 1, 0x80, 0x80, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2, 1, 0]
+        );
+    }
+
+    #[test]
+    fn empty_route_codec() {
+        assert_codec_roundtrip!(Route, Route { addresses: vec![] }, vec![0]);
+    }
+
+    #[test]
+    fn u16_codec() {
+        assert_codec_roundtrip!(u16, 0x7f, vec![0x7f]);
+        assert_codec_roundtrip!(u16, 0x80, vec![0x80, 0x01]);
+        assert_codec_roundtrip!(u16, 0x1300, vec![0x80, 0x13 << 1]);
+        assert_codec_roundtrip!(u16, 0x1381, vec![0x81, (0x13 << 1) | 1]);
+
+        let mut too_big: u16 = 0xC000;
+        let mut u: Vec<u8> = vec![];
+        match u16::encode(&mut too_big, &mut u) {
+            Ok(()) => panic!(),
+            Err(s) => {}
+        }
+        let mut u: Vec<u8> = vec![];
+        assert_eq!(
+            u16::encode(&0x8000, &mut u),
+            Err(MessageError::ValueTooLarge)
+        );
+        assert_codec_roundtrip!(u16, 0x7fff, vec![0xff, 0xff]);
+    }
+
+    #[test]
+    fn message_codec() {
+        let mut onward_addresses: Vec<Address> = vec![];
+        onward_addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            0x8080,
+        ));
+        onward_addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        ));
+        onward_addresses.push(Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x00010203,
+            },
+        ));
         let mut return_addresses: Vec<Address> = vec![];
         return_addresses.push(Address::UdpAddress(
             AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+            0x8080,
+        ));
+        return_addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
+            0x7070,
+        ));
+        return_addresses.push(Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x00010203,
+            },
+        ));
+        let onward_route = Route {
+            addresses: onward_addresses,
+        };
+        let return_route = Route {
+            addresses: return_addresses,
+        };
+        assert_codec_roundtrip!(
+            Message,
+            Message {
+                onward_route,
+                return_route,
+                message_body: vec![0],
+                ..Default::default()
+            },
+            vec![
+                1, 0, 3, 2, 0, 127, 0, 0, 1, 0x80, 0x80, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2,
+                1, 0, 3, 2, 0, 127, 0, 0, 2, 0x80, 0x80, 2, 0, 10, 0, 1, 11, 0x70, 0x70, 0, 3, 2,
+                1, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn route_set_endpoint() {
+        let mut route = Route {
+            addresses: vec![
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    0x8080,
+                ),
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                    0x7070,
+                ),
+                Address::LocalAddress(
+                    AddressType::Local,
+                    LocalAddress {
+                        address: 0x00010203,
+                    },
+                ),
+            ],
+        };
+        let hops = route.addresses[..2].to_vec();
+
+        let endpoint = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
+            0x6060,
+        );
+        assert_eq!(route.set_endpoint(endpoint.clone()), Ok(()));
+        assert_eq!(route.addresses.len(), 3);
+        assert_eq!(route.addresses[..2], hops[..]);
+        assert_eq!(route.addresses[2], endpoint);
+
+        let la = LocalAddress {
+            address: 0x04050607,
+        };
+        assert_eq!(route.set_local_endpoint(la), Ok(()));
+        assert_eq!(route.addresses[..2], hops[..]);
+        assert_eq!(
+            route.addresses[2],
+            Address::LocalAddress(AddressType::Local, la)
+        );
+
+        let mut empty = Route { addresses: vec![] };
+        assert_eq!(empty.set_endpoint(endpoint), Err(MessageError::EmptyRoute));
+        assert_eq!(empty.set_local_endpoint(la), Err(MessageError::EmptyRoute));
+    }
+
+    #[test]
+    fn message_encode_error() {
+        let mut msg = Message::default();
+        msg.onward_route.addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            0x8080,
+        ));
+        msg.return_route.addresses = vec![
+            Address::LocalAddress(
+                AddressType::Local,
+                LocalAddress {
+                    address: 0x00010203,
+                },
+            );
+            256
+        ];
+        let mut u: Vec<u8> = vec![0xff];
+        assert_eq!(
+            Message::encode(&msg, &mut u),
+            Err(MessageError::RouteTooLong(256))
+        );
+        assert_eq!(u, vec![0xff]);
+
+        msg.return_route.addresses.truncate(255);
+        assert_eq!(Message::encode(&msg, &mut u), Ok(()));
+    }
+
+    #[test]
+    fn tunnel_address_codec() {
+        let inner = Route {
+            addresses: vec![
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                    0x7070,
+                ),
+                Address::LocalAddress(
+                    AddressType::Local,
+                    LocalAddress {
+                        address: 0x00010203,
+                    },
+                ),
+            ],
+        };
+        let tunnel = Address::Tunnel(Box::new(inner));
+        let v = vec![3, 14, 2, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2, 1, 0];
+        assert_codec_roundtrip!(Address, tunnel, v.clone());
+
+        // The declared length must cover the nested route exactly
+        assert_eq!(
+            Address::decode(&v[..15]),
+            Err(MessageError::BufferTooShort {
+                needed: 14,
+                got: 13
+            })
+        );
+        let mut long = v.clone();
+        long[1] = 15;
+        long.push(0);
+        assert_eq!(Address::decode(&long), Err(MessageError::TrailingBytes(1)));
+    }
+
+    #[test]
+    fn route_flatten() {
+        let udp = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            0x8080,
+        );
+        let inner_udp = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        );
+        let local = Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x00010203,
+            },
+        );
+        let route = Route {
+            addresses: vec![
+                udp.clone(),
+                Address::Tunnel(Box::new(Route {
+                    addresses: vec![inner_udp.clone(), local.clone()],
+                })),
+                local.clone(),
+            ],
+        };
+        let mut v: Vec<u8> = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        let (decoded, w) = Route::decode(&v).unwrap();
+        assert_eq!(decoded, route);
+        assert_eq!(w.len(), 0);
+
+        let flat = decoded.flatten();
+        assert_eq!(flat.addresses, vec![udp, inner_udp, local.clone(), local]);
+    }
+
+    #[test]
+    fn tunnel_depth_limit() {
+        let local = Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x00010203,
+            },
+        );
+        let mut route = Route {
+            addresses: vec![local.clone()],
+        };
+        for _ in 0..MAX_TUNNEL_DEPTH {
+            route = Route {
+                addresses: vec![Address::Tunnel(Box::new(route))],
+            };
+        }
+        let mut v: Vec<u8> = vec![];
+        assert_eq!(Route::encode(&route, &mut v), Ok(()));
+        assert_eq!(Route::decode(&v).unwrap().0, route);
+        assert_eq!(route.flatten().addresses, vec![local]);
+
+        let route = Route {
+            addresses: vec![Address::Tunnel(Box::new(route))],
+        };
+        let mut v: Vec<u8> = vec![];
+        assert_eq!(
+            Route::encode(&route, &mut v),
+            Err(MessageError::NestingTooDeep)
+        );
+    }
+
+    #[test]
+    fn message_debug_json() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                        0x8080,
+                    ),
+                    Address::LocalAddress(
+                        AddressType::Local,
+                        LocalAddress {
+                            address: 0x00010203,
+                        },
+                    ),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![Address::TcpAddress(
+                    AddressType::Tcp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
+                    0x7070,
+                )],
+            },
+            message_body: vec![0xde, 0xad, 0x01],
+            ..Default::default()
+        };
+        let json = msg.to_debug_json();
+        assert!(json.contains("\"udp://127.0.0.1:32896\""));
+        assert!(json.contains("\"local://00010203\""));
+        assert!(json.contains("\"tcp://10.0.1.11:28784\""));
+        assert!(json.contains("\"body\":\"dead01\""));
+        assert_eq!(
+            json,
+            "{\"onward\":[\"udp://127.0.0.1:32896\",\"local://00010203\"],\
+             \"return\":[\"tcp://10.0.1.11:28784\"],\"body\":\"dead01\"}"
+        );
+    }
+
+    #[test]
+    fn message_decode_into() {
+        let first = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                        0x8080,
+                    ),
+                    Address::LocalAddress(
+                        AddressType::Local,
+                        LocalAddress {
+                            address: 0x00010203,
+                        },
+                    ),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
+                    0x7070,
+                )],
+            },
+            message_body: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            ..Default::default()
+        };
+        let second = Message {
+            onward_route: Route {
+                addresses: vec![Address::LocalAddress(
+                    AddressType::Local,
+                    LocalAddress {
+                        address: 0x04050607,
+                    },
+                )],
+            },
+            return_route: Route { addresses: vec![] },
+            message_body: vec![9, 10],
+            ..Default::default()
+        };
+        let mut u1: Vec<u8> = vec![];
+        Message::encode(&first, &mut u1).unwrap();
+        let mut u2: Vec<u8> = vec![];
+        Message::encode(&second, &mut u2).unwrap();
+
+        let mut msg = Message::default();
+        assert_eq!(msg.decode_into(&u1), Ok(u1.len()));
+        assert_eq!(msg.onward_route, first.onward_route);
+        assert_eq!(msg.return_route, first.return_route);
+        assert_eq!(msg.message_body, first.message_body);
+
+        let body = msg.message_body.as_ptr();
+        let onward = msg.onward_route.addresses.as_ptr();
+        assert_eq!(msg.decode_into(&u2), Ok(u2.len()));
+        assert_eq!(msg.onward_route, second.onward_route);
+        assert_eq!(msg.return_route, second.return_route);
+        assert_eq!(msg.message_body, second.message_body);
+        assert_eq!(msg.message_body.as_ptr(), body);
+        assert_eq!(msg.onward_route.addresses.as_ptr(), onward);
+    }
+
+    #[test]
+    fn shared_routes_codec() {
+        let shared = vec![
+            Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                0x8080,
+            ),
+            Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                0x7070,
+            ),
+        ];
+        let mut onward = Route {
+            addresses: shared.clone(),
+        };
+        onward.addresses.push(Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x00010203,
+            },
+        ));
+        let mut return_route = Route { addresses: shared };
+        return_route.addresses.push(Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x04050607,
+            },
+        ));
+
+        let mut full: Vec<u8> = vec![];
+        Route::encode(&onward, &mut full).unwrap();
+        Route::encode(&return_route, &mut full).unwrap();
+        let mut v: Vec<u8> = vec![];
+        encode_routes_shared(&onward, &return_route, &mut v).unwrap();
+        // The two shared 8 byte hops are written once, at the cost of one more count byte
+        assert!(v.len() < full.len());
+        assert_eq!(full.len() - v.len(), 16 - 1);
+
+        v.push(0xff);
+        let (o, r, w) = decode_routes_shared(&v).unwrap();
+        assert_eq!(o, onward);
+        assert_eq!(r, return_route);
+        assert_eq!(w, &[0xff]);
+    }
+
+    #[test]
+    fn address_constructors() {
+        assert_eq!(
+            Address::udp_loopback(32896),
+            Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                0x8080
+            )
+        );
+        assert_eq!(
+            Address::tcp_loopback(32896),
+            Address::TcpAddress(
+                AddressType::Tcp,
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                0x8080
+            )
+        );
+        assert_ne!(Address::udp_loopback(32896), Address::tcp_loopback(32896));
+        assert_eq!(
+            Address::local(0x00010203),
+            Address::LocalAddress(
+                AddressType::Local,
+                LocalAddress {
+                    address: 0x00010203
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn route_clone_from() {
+        let source = Route {
+            addresses: vec![
+                Address::udp_loopback(0x8080),
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                    0x7070,
+                ),
+                Address::local(0x00010203),
+            ],
+        };
+        let mut route = Route {
+            addresses: Vec::with_capacity(8),
+        };
+        route.addresses.push(Address::local(0x04050607));
+        let capacity = route.addresses.capacity();
+        route.clone_from(&source);
+        assert_eq!(route, source);
+        assert_eq!(route.addresses.capacity(), capacity);
+
+        route.clone_from(&Route { addresses: vec![] });
+        assert!(route.addresses.is_empty());
+    }
+
+    #[test]
+    fn tunnel_decode_max_depth() {
+        // A route of one local hop, wrapped in a tunnel hop 20 times
+        let mut v: Vec<u8> = vec![1, 0, 3, 2, 1, 0];
+        for _ in 0..20 {
+            let mut outer: Vec<u8> = vec![1, 3];
+            u16::encode(&(v.len() as u16), &mut outer).unwrap();
+            outer.append(&mut v);
+            v = outer;
+        }
+        assert_eq!(Route::decode(&v), Err(MessageError::NestingTooDeep));
+
+        let config = DecodeConfig {
+            max_depth: 19,
+            ..Default::default()
+        };
+        assert_eq!(
+            Route::decode_with_config(&v, &config),
+            Err(MessageError::NestingTooDeep)
+        );
+        let config = DecodeConfig {
+            max_depth: 20,
+            ..Default::default()
+        };
+        let (route, w) = Route::decode_with_config(&v, &config).unwrap();
+        assert_eq!(w.len(), 0);
+        assert_eq!(route.flatten().addresses, vec![Address::local(0x00010203)]);
+
+        // Routes without tunnel hops aren't affected by the limit
+        let config = DecodeConfig {
+            max_depth: 0,
+            ..Default::default()
+        };
+        let (route, _) = Route::decode_with_config(&[1, 0, 3, 2, 1, 0], &config).unwrap();
+        assert_eq!(route.addresses, vec![Address::local(0x00010203)]);
+    }
+
+    #[test]
+    fn message_reply_expected() {
+        let mut msg = Message::default();
+        assert!(!msg.reply_expected());
+        msg.set_reply_expected(true);
+        assert!(msg.reply_expected());
+        msg.set_reply_expected(false);
+        assert!(!msg.reply_expected());
+
+        for &expected in [false, true].iter() {
+            for return_route in [
+                Route { addresses: vec![] },
+                Route {
+                    addresses: vec![Address::udp_loopback(0x8080), Address::local(0x00010203)],
+                },
+            ]
+            .iter()
+            {
+                let mut msg = Message {
+                    onward_route: Route {
+                        addresses: vec![Address::local(0x04050607)],
+                    },
+                    return_route: return_route.clone(),
+                    ..Default::default()
+                };
+                msg.set_reply_expected(expected);
+                let mut u: Vec<u8> = vec![];
+                Message::encode(&msg, &mut u).unwrap();
+                assert_eq!(u[1], if expected { FLAG_REPLY_EXPECTED } else { 0 });
+                let (m, _) = Message::decode(&u).unwrap();
+                assert_eq!(m.reply_expected(), expected);
+                assert_eq!(&m.return_route, return_route);
+            }
+        }
+
+        assert_eq!(
+            Message::decode(&[1, FLAG_EXTENDED, 0x80, 0, 0]).unwrap_err(),
+            MessageError::UnknownFlags(0x80)
+        );
+    }
+
+    #[test]
+    fn message_decode_onward_first() {
+        let first = Address::udp_loopback(0x8080);
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    first.clone(),
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                        0x7070,
+                    ),
+                    Address::local(0x00010203),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x04050607)],
+            },
+            message_body: vec![7, 8, 9],
+            ..Default::default()
+        };
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+
+        let (a, rest) = Message::decode_onward_first(&u).unwrap();
+        assert_eq!(a, first);
+        assert_eq!(rest, &u[11..]);
+
+        // Corrupt the type of the second onward hop
+        let mut corrupt = u.clone();
+        corrupt[11] = 0x7f;
+        assert!(Message::decode(&corrupt).is_err());
+        let (a, rest) = Message::decode_onward_first(&corrupt).unwrap();
+        assert_eq!(a, first);
+        assert_eq!(rest, &corrupt[11..]);
+
+        // Corrupt the return route
+        let mut corrupt = u.clone();
+        corrupt[25] = 0x7f;
+        assert!(Message::decode(&corrupt).is_err());
+        assert_eq!(Message::decode_onward_first(&corrupt).unwrap().0, first);
+
+        // Only an unreadable first hop is an error
+        let mut corrupt = u.clone();
+        corrupt[3] = 0x7f;
+        assert_eq!(
+            Message::decode_onward_first(&corrupt).unwrap_err(),
+            MessageError::UnknownAddressType(0x7f)
+        );
+        assert_eq!(
+            Message::decode_onward_first(&[1, 0, 0, 0]).unwrap_err(),
+            MessageError::EmptyRoute
+        );
+        assert_eq!(
+            Message::decode_onward_first(&[1, 0, 10, 0, 3, 2, 1, 0]).unwrap_err(),
+            MessageError::RouteTruncated { count: 10, got: 5 }
+        );
+    }
+
+    #[test]
+    fn route_same_set() {
+        let udp = Address::udp_loopback(0x8080);
+        let tcp = Address::tcp_loopback(0x8080);
+        let local = Address::local(0x00010203);
+        let route = Route {
+            addresses: vec![udp.clone(), tcp.clone(), local.clone()],
+        };
+        let reordered = Route {
+            addresses: vec![local.clone(), udp.clone(), tcp.clone()],
+        };
+        assert!(route.same_set(&reordered));
+        assert!(reordered.same_set(&route));
+        assert!(route != reordered);
+        assert!(route.same_set(&route));
+
+        // Repeated hops have to match in number too
+        let doubled = Route {
+            addresses: vec![udp.clone(), udp.clone(), local.clone()],
+        };
+        let once = Route {
+            addresses: vec![udp.clone(), tcp, local],
+        };
+        assert!(!doubled.same_set(&once));
+        assert!(!once.same_set(&doubled));
+        assert!(!route.same_set(&Route {
+            addresses: vec![udp]
+        }));
+    }
+
+    #[test]
+    fn handshake_codec() {
+        assert_codec_roundtrip!(
+            Handshake,
+            Handshake {
+                min_version: 1,
+                max_version: 0x1300,
+            },
+            vec![1, 0x80, 0x13 << 1]
+        );
+    }
+
+    #[test]
+    fn handshake_negotiate() {
+        let local = Handshake {
+            min_version: 1,
+            max_version: 4,
+        };
+        let remote = Handshake {
+            min_version: 3,
+            max_version: 7,
+        };
+        assert_eq!(Handshake::negotiate(&local, &remote), Some(4));
+        assert_eq!(Handshake::negotiate(&remote, &local), Some(4));
+        let remote = Handshake {
+            min_version: 4,
+            max_version: 4,
+        };
+        assert_eq!(Handshake::negotiate(&local, &remote), Some(4));
+        let remote = Handshake {
+            min_version: 5,
+            max_version: 7,
+        };
+        assert_eq!(Handshake::negotiate(&local, &remote), None);
+        assert_eq!(Handshake::negotiate(&remote, &local), None);
+    }
+
+    #[test]
+    fn message_route_summary() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::udp_loopback(0x8080),
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                        0x7070,
+                    ),
+                    Address::local(0x00010203),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+                        0x8080,
+                    ),
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
+                        0x7070,
+                    ),
+                    Address::local(0x00010203),
+                ],
+            },
+            message_body: vec![0],
+            ..Default::default()
+        };
+        assert_eq!(
+            msg.route_summary(),
+            RouteSummary {
+                onward_hops: 3,
+                return_hops: 3,
+                body_len: 1,
+                remote_hops: 4,
+                local_hops: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn varint_port_format() {
+        let varint = WireFormat {
+            varint_ports: true,
+            ..Default::default()
+        };
+        let address = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            80,
+        );
+
+        let mut v: Vec<u8> = vec![];
+        Address::encode_with_format(&address, &mut v, varint).unwrap();
+        assert_eq!(v, vec![2, 0, 127, 0, 0, 1, 80]);
+        let (decoded, w) = Address::decode_with_format(&v, varint).unwrap();
+        assert_eq!(decoded, address);
+        assert_eq!(w.len(), 0);
+
+        // The default format still uses two bytes
+        assert_codec_roundtrip!(Address, address.clone(), vec![2, 0, 127, 0, 0, 1, 80, 0]);
+
+        // A message records its format in the header
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![address.clone(), Address::udp_loopback(0x7070)],
+            },
+            ..Default::default()
+        };
+        let mut fixed: Vec<u8> = vec![];
+        Message::encode(&msg, &mut fixed).unwrap();
+        let mut u: Vec<u8> = vec![];
+        Message::encode_with_format(&msg, &mut u, varint).unwrap();
+        assert_eq!(u[1], FLAG_VARINT_PORTS);
+        assert_eq!(u.len(), fixed.len() - 1);
+        let (m, _) = Message::decode(&u).unwrap();
+        assert_eq!(m.onward_route, msg.onward_route);
+        assert_eq!(m.flags, 0);
+
+        let mut v: Vec<u8> = vec![];
+        assert_eq!(
+            Address::encode_with_format(&Address::udp_loopback(0x8080), &mut v, varint),
+            Err(MessageError::ValueTooLarge)
+        );
+    }
+
+    #[test]
+    fn route_decode_with_policy() {
+        let loopback = Route {
+            addresses: vec![
+                Address::udp_loopback(0x8080),
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+                    0x7070,
+                ),
+                Address::local(0x00010203),
+            ],
+        };
+        let mut v: Vec<u8> = vec![];
+        Route::encode(&loopback, &mut v).unwrap();
+        let (route, w) = Route::decode_with_policy(&v, &LoopbackOnlyPolicy).unwrap();
+        assert_eq!(route, loopback);
+        assert_eq!(w.len(), 0);
+
+        let public =
+            Address::UdpAddress(AddressType::Udp, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+        let mut route = loopback.clone();
+        route.addresses.insert(1, public.clone());
+        let mut v: Vec<u8> = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        assert_eq!(
+            Route::decode_with_policy(&v, &LoopbackOnlyPolicy),
+            Err(MessageError::PolicyViolation(
+                "udp://8.8.8.8:53".to_string()
+            ))
+        );
+
+        // Public hops hidden inside a tunnel are found too
+        let tunneled = Route {
+            addresses: vec![
+                Address::udp_loopback(0x8080),
+                Address::Tunnel(Box::new(Route {
+                    addresses: vec![public],
+                })),
+            ],
+        };
+        let mut v: Vec<u8> = vec![];
+        Route::encode(&tunneled, &mut v).unwrap();
+        assert!(Route::decode_with_policy(&v, &LoopbackOnlyPolicy).is_err());
+    }
+
+    #[test]
+    fn message_error_io_conversion() {
+        let err: std::io::Error = MessageError::BufferTooShort { needed: 4, got: 2 }.into();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            MessageError::from(err),
+            MessageError::BufferTooShort { needed: 4, got: 2 }
+        );
+
+        let err: std::io::Error = MessageError::UnknownAddressType(9).into();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err: std::io::Error = MessageError::EmptyRoute.into();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let err = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        assert_eq!(
+            MessageError::from(err),
+            MessageError::Io(std::io::ErrorKind::BrokenPipe)
+        );
+
+        let mut msg = Message {
+            message_body: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let mut buf = [0u8; 2];
+        let err = std::io::Read::read(&mut msg, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn prepend_return_hop_forwarding() {
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x7070), Address::local(0x0102_0304)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x0a0b_0c0d)],
+            },
+            ..Default::default()
+        };
+
+        // One forward step: take the next hop off the onward route and record where the
+        // message came from on the return route
+        let next = msg.onward_route.addresses.remove(0);
+        assert_eq!(next, Address::udp_loopback(0x7070));
+        msg.prepend_return_hop(Address::udp_loopback(0x6060))
+            .unwrap();
+        assert_eq!(
+            msg.onward_route.addresses,
+            vec![Address::local(0x0102_0304)]
+        );
+        assert_eq!(
+            msg.return_route.addresses,
+            vec![Address::udp_loopback(0x6060), Address::local(0x0a0b_0c0d)]
+        );
+
+        while msg.return_route.addresses.len() < MAX_ROUTE_HOPS {
+            msg.prepend_return_hop(Address::local(0)).unwrap();
+        }
+        assert_eq!(
+            msg.prepend_return_hop(Address::local(0)),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+        assert_eq!(msg.return_route.addresses.len(), MAX_ROUTE_HOPS);
+    }
+
+    #[test]
+    fn route_skip_matches_decode() {
+        let routes = [
+            Route { addresses: vec![] },
+            Route {
+                addresses: vec![Address::local(0x0102_0304), Address::udp_loopback(0x7070)],
+            },
+            Route {
+                addresses: vec![
+                    Address::udp_loopback(0x1010),
+                    Address::Tunnel(Box::new(Route {
+                        addresses: vec![Address::local(1), Address::udp_loopback(0x2020)],
+                    })),
+                    Address::local(2),
+                ],
+            },
+            Route {
+                addresses: vec![Address::tcp_loopback(0x7070), Address::local(3)],
+            },
+        ];
+        for route in routes.iter() {
+            let mut encoded = vec![];
+            Route::encode(route, &mut encoded).unwrap();
+            encoded.extend_from_slice(&[0xaa, 0xbb]);
+            let (decoded, decoded_rest) = Route::decode(&encoded).unwrap();
+            assert_eq!(&decoded, route);
+            assert_eq!(Route::skip(&encoded).unwrap(), decoded_rest);
+            assert_eq!(Route::skip(&encoded).unwrap(), &[0xaa, 0xbb]);
+        }
+
+        let mut encoded = vec![];
+        Route::encode(
+            &Route {
+                addresses: vec![Address::tcp_loopback(0x7070)],
+            },
+            &mut encoded,
+        )
+        .unwrap();
+        assert_eq!(Route::skip(&encoded).unwrap(), &[] as &[u8]);
+        assert_eq!(
+            Route::skip(&encoded[..encoded.len() - 1]),
+            Err(MessageError::BufferTooShort { needed: 7, got: 6 })
+        );
+    }
+
+    #[test]
+    fn custom_address_registry() {
+        // A custom address is a name, written with a one byte length prefix
+        let mut registry = AddressCodecRegistry::new();
+        registry
+            .register(
+                0x40,
+                |data, v| {
+                    v.push(data.len() as u8);
+                    v.extend_from_slice(data);
+                    Ok(())
+                },
+                |u| match u.split_first() {
+                    Some((len, rest)) if rest.len() >= *len as usize => {
+                        Ok((rest[..*len as usize].to_vec(), 1 + *len as usize))
+                    }
+                    _ => Err(MessageError::BufferTooShort {
+                        needed: 1,
+                        got: u.len(),
+                    }),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            registry.register(3, |_, _| Ok(()), |_| Ok((vec![], 0))),
+            Err(MessageError::ReservedAddressType(3))
+        );
+        let registry = std::sync::Arc::new(registry);
+
+        let address = Address::Custom(0x40, b"relay".to_vec());
+        let mut v = vec![];
+        Address::encode_with_registry(&address, &mut v, &registry).unwrap();
+        assert_eq!(v, vec![0x40, 5, b'r', b'e', b'l', b'a', b'y']);
+        let (decoded, rest) = Address::decode_with_registry(&v, &registry).unwrap();
+        assert_eq!(decoded, address);
+        assert!(rest.is_empty());
+
+        // Without the registry the type is unknown both ways
+        assert_eq!(
+            Address::encode(&address, &mut vec![]),
+            Err(MessageError::UnknownAddressType(0x40))
+        );
+        assert_eq!(
+            Address::decode(&v),
+            Err(MessageError::UnknownAddressType(0x40))
+        );
+
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![address, Address::local(7)],
+            },
+            message_body: vec![1, 2, 3],
+            ..Default::default()
+        };
+        let mut v = vec![];
+        Message::encode_with_registry(&msg, &mut v, &registry).unwrap();
+        let (decoded, _) = Message::decode_with_registry(&v, &registry).unwrap();
+        assert_eq!(decoded.onward_route, msg.onward_route);
+        assert_eq!(decoded.message_body, msg.message_body);
+
+        // Skipping a route holding a custom address needs the registry too. The onward route
+        // follows the version and the flags, and the empty return route and the body follow it.
+        let onward = &v[2..];
+        assert_eq!(
+            Route::skip(onward),
+            Err(MessageError::UnknownAddressType(0x40))
+        );
+        assert_eq!(
+            Route::skip_with_registry(onward, &registry),
+            Ok(&[0, 1, 2, 3][..])
+        );
+    }
+
+    #[test]
+    fn route_encoded_hash() {
+        let built = Route {
+            addresses: vec![Address::udp_loopback(0x7070), Address::local(0x0102_0304)],
+        };
+        let mut pushed = Route { addresses: vec![] };
+        pushed.addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            0x7070,
+        ));
+        pushed.addresses.push(Address::LocalAddress(
+            AddressType::Local,
+            LocalAddress {
+                address: 0x0102_0304,
+            },
+        ));
+        assert_eq!(built.encoded_hash(), pushed.encoded_hash());
+
+        let mut decoded = vec![];
+        Route::encode(&built, &mut decoded).unwrap();
+        let (decoded, _) = Route::decode(&decoded).unwrap();
+        assert_eq!(built.encoded_hash(), decoded.encoded_hash());
+
+        let other = Route {
+            addresses: vec![Address::local(0x0102_0304), Address::udp_loopback(0x7070)],
+        };
+        assert_ne!(built.encoded_hash(), other.encoded_hash());
+
+        // The hash is the same FNV-1a as `body_hash`, taken over the encoding
+        let mut encoded = vec![];
+        Route::encode(&built, &mut encoded).unwrap();
+        let body = Message {
+            message_body: encoded,
+            ..Default::default()
+        };
+        assert_eq!(built.encoded_hash(), Ok(body.body_hash()));
+
+        let custom = Route {
+            addresses: vec![Address::Custom(0x80, vec![1])],
+        };
+        assert!(custom.encoded_hash().is_err());
+    }
+
+    #[test]
+    fn decode_empty_input() {
+        let empty = MessageError::BufferTooShort { needed: 1, got: 0 };
+        assert_eq!(Message::decode(&[]).err(), Some(empty.clone()));
+        assert_eq!(
+            LocalAddress::decode(&[1, 2, 3]).err(),
+            Some(MessageError::BufferTooShort { needed: 4, got: 3 })
+        );
+        assert_eq!(Route::decode(&[]).err(), Some(empty.clone()));
+        assert_eq!(Address::decode(&[]).err(), Some(empty.clone()));
+        // A route whose count promises an address that isn't there
+        assert_eq!(
+            Route::decode(&[1]).err(),
+            Some(MessageError::RouteTruncated { count: 1, got: 0 })
+        );
+    }
+
+    #[test]
+    fn push_onward_source_route() {
+        // Intended path: udp 0x1010 -> udp 0x2020 -> local 7
+        let mut msg = Message::default();
+        msg.push_onward_back(Address::local(7)).unwrap();
+        msg.push_onward_front(Address::udp_loopback(0x2020))
+            .unwrap();
+        msg.push_onward_front(Address::udp_loopback(0x1010))
+            .unwrap();
+        assert_eq!(
+            msg.onward_route.addresses,
+            vec![
+                Address::udp_loopback(0x1010),
+                Address::udp_loopback(0x2020),
+                Address::local(7)
+            ]
+        );
+
+        let mut msg = Message::default();
+        for i in 0..MAX_ROUTE_HOPS {
+            msg.push_onward_back(Address::local(i as u32)).unwrap();
+        }
+        assert_eq!(
+            msg.push_onward_front(Address::local(0)),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+        assert_eq!(
+            msg.push_onward_back(Address::local(0)),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+    }
+
+    #[test]
+    fn ipv4_decode_short_buffer() {
+        assert_eq!(
+            IpAddr::decode(&[0, 127, 0, 0]),
+            Err(MessageError::BufferTooShort { needed: 4, got: 3 })
+        );
+        assert_eq!(
+            IpAddr::decode(&[]),
+            Err(MessageError::BufferTooShort { needed: 1, got: 0 })
+        );
+        let (ip, rest) = IpAddr::decode(&[0, 127, 0, 0, 1, 0xaa]).unwrap();
+        assert_eq!(ip, IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        assert_eq!(rest, &[0xaa]);
+        // A UDP address that short is rejected up front
+        assert_eq!(
+            Address::decode(&[2, 0, 127, 0, 0]),
+            Err(MessageError::BufferTooShort { needed: 8, got: 5 })
+        );
+    }
+
+    #[test]
+    fn route_from_endpoints() {
+        let endpoints = vec![
+            (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0x8080),
+            (IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)), 0x7070),
+        ];
+        let route = Route::from_udp_endpoints(endpoints.clone());
+        let mut expected: Route = Route { addresses: vec![] };
+        expected.addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            0x8080,
+        ));
+        expected.addresses.push(Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        ));
+        assert_eq!(route, expected);
+
+        let route = Route::from_tcp_endpoints(endpoints);
+        assert_eq!(
+            route.addresses,
+            vec![
+                Address::TcpAddress(
+                    AddressType::Tcp,
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    0x8080
+                ),
+                Address::TcpAddress(
+                    AddressType::Tcp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                    0x7070
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_full_path() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x1010), Address::local(1)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(2), Address::udp_loopback(0x2020)],
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            msg.full_path().addresses,
+            vec![
+                Address::udp_loopback(0x1010),
+                Address::local(1),
+                Address::udp_loopback(0x2020),
+                Address::local(2)
+            ]
+        );
+        assert_eq!(Message::default().full_path().addresses, vec![]);
+    }
+
+    #[test]
+    fn message_decode_with_version() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x7070), Address::local(0x00010203)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x04050607)],
+            },
+            message_body: vec![7, 8, 9],
+            ..Default::default()
+        };
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u[0], WIRE_PROTOCOL_VERSION as u8);
+
+        // Once the version is agreed, frames can leave it out
+        let headerless = &u[1..];
+        let m = Message::decode_with_version(headerless, 1).unwrap();
+        assert_eq!(m.onward_route, msg.onward_route);
+        assert_eq!(m.return_route, msg.return_route);
+        assert_eq!(m.message_body, msg.message_body);
+
+        assert_eq!(
+            Message::decode_with_version(headerless, 2).unwrap_err(),
+            MessageError::UnsupportedVersion(2)
+        );
+        let mut unsupported = u.clone();
+        unsupported[0] = 2;
+        assert_eq!(
+            Message::decode(&unsupported).unwrap_err(),
+            MessageError::UnsupportedVersion(2)
+        );
+    }
+
+    #[test]
+    fn route_locality() {
+        let mixed = Route {
+            addresses: vec![
+                Address::udp_loopback(0x8080),
+                Address::udp_loopback(0x7070),
+                Address::local(0x00010203),
+            ],
+        };
+        assert!(!mixed.is_local_only());
+        assert!(!mixed.is_remote_only());
+        assert!(mixed.has_remote());
+
+        let local = Route {
+            addresses: vec![Address::local(1), Address::local(2)],
+        };
+        assert!(local.is_local_only());
+        assert!(!local.is_remote_only());
+        assert!(!local.has_remote());
+
+        let remote = Route {
+            addresses: vec![
+                Address::udp_loopback(0x8080),
+                Address::Tunnel(Box::new(Route {
+                    addresses: vec![Address::local(1)],
+                })),
+            ],
+        };
+        assert!(!remote.is_local_only());
+        assert!(remote.is_remote_only());
+        assert!(remote.has_remote());
+    }
+
+    #[test]
+    fn route_decode_long_route_small_stack() {
+        // Decode on a thread with a deliberately small stack; a decoder that recursed per hop
+        // would overflow it
+        let handle = std::thread::Builder::new()
+            .stack_size(64 * 1024)
+            .spawn(|| {
+                for hops in [MAX_ROUTE_HOPS, u8::MAX as usize].iter() {
+                    let route = Route {
+                        addresses: (0..*hops).map(|i| Address::local(i as u32)).collect(),
+                    };
+                    let mut v = vec![];
+                    Route::encode(&route, &mut v).unwrap();
+                    let (decoded, rest) = Route::decode(&v).unwrap();
+                    assert_eq!(decoded, route);
+                    assert!(rest.is_empty());
+                }
+            })
+            .unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn message_encode_reserves_once() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::udp_loopback(0x8080),
+                    Address::Tunnel(Box::new(Route {
+                        addresses: vec![Address::local(1), Address::udp_loopback(0x7070)],
+                    })),
+                    Address::local(0x00010203),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x04050607)],
+            },
+            message_body: vec![0xab; 64 * 1024],
+            ..Default::default()
+        };
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u.len(), msg.encoded_len());
+        // The single up front reservation was enough, so the buffer never had to grow
+        assert_eq!(u.capacity(), msg.encoded_len());
+    }
+
+    #[test]
+    fn address_same_endpoint() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let tcp = Address::TcpAddress(AddressType::Tcp, ip, 443);
+        let udp = Address::UdpAddress(AddressType::Udp, ip, 443);
+        assert!(tcp.same_endpoint(&udp));
+        assert!(udp.same_endpoint(&tcp));
+        assert_ne!(tcp, udp);
+
+        assert!(!tcp.same_endpoint(&Address::udp_loopback(444)));
+        assert!(!Address::local(1).same_endpoint(&Address::local(1)));
+    }
+
+    #[test]
+    fn message_decode_header_borrows_body() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x7070), Address::local(0x00010203)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x04050607)],
+            },
+            message_body: vec![7, 8, 9],
+            ..Default::default()
+        };
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+
+        let (onward, ret, body) = Message::decode_header(&u).unwrap();
+        assert_eq!(onward, msg.onward_route);
+        assert_eq!(ret, msg.return_route);
+        assert_eq!(body, &[7, 8, 9]);
+        // The body is the tail of the frame itself, not a copy
+        let tail = &u[u.len() - 3..];
+        assert_eq!(body.as_ptr(), tail.as_ptr());
+    }
+
+    #[test]
+    fn aliased_address() {
+        let udp = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        );
+        let aliased = Address::aliased(udp.clone(), "gateway");
+        let mut expected = vec![5, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 7];
+        expected.extend_from_slice(b"gateway");
+        assert_codec_roundtrip!(Address, aliased.clone(), expected.clone());
+        assert_eq!(aliased.encoded_len(), expected.len());
+
+        assert_eq!(aliased.to_string(), "gateway");
+        assert_eq!(aliased.unaliased(), &udp);
+        assert!(aliased.same_endpoint(&udp));
+
+        let route = Route {
+            addresses: vec![aliased, Address::local(1)],
+        };
+        assert!(!route.is_local_only());
+        assert_eq!(route.to_string(), "[gateway, local://00000001]");
+        let mut v = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        v.push(0xaa);
+        assert_eq!(Route::skip(&v).unwrap(), &[0xaa]);
+
+        let mut bad = expected.clone();
+        bad[10] = 0xff;
+        assert_eq!(Address::decode(&bad), Err(MessageError::InvalidUtf8));
+    }
+
+    #[test]
+    fn message_pool_reuses_buffers() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x7070), Address::local(0x00010203)],
+            },
+            message_body: vec![0xab; 4096],
+            ..Default::default()
+        };
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+
+        let pool = MessagePool::new(4);
+        let (capacity, body) = {
+            let pooled = pool.decode(&u).unwrap();
+            assert_eq!(pooled.onward_route, msg.onward_route);
+            assert_eq!(pooled.message_body, msg.message_body);
+            (pooled.message_body.capacity(), pooled.message_body.as_ptr())
+        };
+        assert_eq!(pool.idle(), 1);
+
+        let pooled = pool.acquire();
+        assert_eq!(pool.idle(), 0);
+        assert!(pooled.message_body.is_empty());
+        assert!(pooled.onward_route.addresses.is_empty());
+        assert_eq!(pooled.message_body.capacity(), capacity);
+        assert_eq!(pooled.message_body.as_ptr(), body);
+    }
+
+    #[test]
+    fn local_address_null() {
+        assert!(LocalAddress::NULL.is_null());
+        assert!(LocalAddress { address: 0 }.is_null());
+        assert!(!LocalAddress { address: 1 }.is_null());
+
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x7070), Address::local(0)],
+            },
+            ..Default::default()
+        };
+        let strict = ValidateOptions {
+            reject_null_endpoint: true,
+        };
+        assert_eq!(msg.validate(&ValidateOptions::default()), Ok(()));
+        assert_eq!(msg.validate(&strict), Err(MessageError::NullLocalAddress));
+
+        msg.onward_route.set_endpoint(Address::local(7)).unwrap();
+        assert_eq!(msg.validate(&strict), Ok(()));
+
+        msg.return_route.addresses = vec![Address::local(1); MAX_ROUTE_HOPS + 1];
+        assert_eq!(
+            msg.validate(&ValidateOptions::default()),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+    }
+
+    #[test]
+    fn u64_codec() {
+        assert_codec_roundtrip!(u64, 0, vec![0]);
+        assert_codec_roundtrip!(u64, 0x7f, vec![0x7f]);
+        assert_codec_roundtrip!(u64, 0x80, vec![0x80, 0x01]);
+        assert_codec_roundtrip!(u64, 0x1381, vec![0x81, 0x27]);
+        let mut max = vec![0xff; 9];
+        max.push(0x01);
+        assert_codec_roundtrip!(u64, u64::MAX, max.clone());
+
+        max[9] = 0x02;
+        assert_eq!(u64::decode(&max), Err(MessageError::ValueTooLarge));
+        assert_eq!(
+            u64::decode(&[0x80, 0x80]),
+            Err(MessageError::BufferTooShort { needed: 3, got: 2 })
+        );
+    }
+
+    #[test]
+    fn blob_codec() {
+        assert_codec_roundtrip!(Blob, Blob(vec![]), vec![0]);
+        assert_codec_roundtrip!(Blob, Blob(vec![1, 2, 3]), vec![3, 1, 2, 3]);
+
+        let mut v = vec![];
+        Blob::encode(&Blob(vec![7; 200]), &mut v).unwrap();
+        assert_eq!(&v[..2], &[0xc8, 0x01]);
+        assert_eq!(
+            Blob::decode_with_max(&v, 199),
+            Err(MessageError::ValueTooLarge)
+        );
+        let (blob, rest) = Blob::decode_with_max(&v, 200).unwrap();
+        assert_eq!(blob.0.len(), 200);
+        assert!(rest.is_empty());
+        assert_eq!(
+            Blob::decode(&v[..100]),
+            Err(MessageError::BufferTooShort {
+                needed: 200,
+                got: 98
+            })
+        );
+    }
+
+    #[test]
+    fn message_decode_counting() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            ..Default::default()
+        };
+        let mut valid: Vec<u8> = vec![];
+        Message::encode(&msg, &mut valid).unwrap();
+        let frames: Vec<&[u8]> = vec![
+            &valid,
+            &valid,
+            &[],
+            &[1],
+            &[1, FLAG_EXTENDED, 0x80, 0, 0],
+            &[1, 0, 1, 0x7f, 0, 0],
+            &valid,
+        ];
+
+        let mut stats = DecodeStats::default();
+        for frame in frames {
+            let _ = Message::decode_counting(frame, &mut stats);
+        }
+        assert_eq!(stats.messages_decoded, 3);
+        assert_eq!(stats.errors_by_kind.get("BufferTooShort"), Some(&2));
+        assert_eq!(stats.errors_by_kind.get("UnknownFlags"), Some(&1));
+        assert_eq!(stats.errors_by_kind.get("UnknownAddressType"), Some(&1));
+        assert_eq!(stats.errors_by_kind.len(), 3);
+    }
+
+    #[test]
+    fn route_retain() {
+        let mut route = Route {
+            addresses: vec![
+                Address::udp_loopback(0x8080),
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                    0x7070,
+                ),
+                Address::local(0x00010203),
+            ],
+        };
+        route.retain(|a| matches!(a, Address::LocalAddress(_, _)));
+        assert_eq!(route.addresses, vec![Address::local(0x00010203)]);
+        assert!(route.is_local_only());
+    }
+
+    #[test]
+    fn wire_byte_order() {
+        assert_eq!(WIRE_BYTE_ORDER, "little-endian");
+
+        let mut v = vec![];
+        LocalAddress::encode(
+            &LocalAddress {
+                address: 0x0102_0304,
+            },
+            &mut v,
+        )
+        .unwrap();
+        assert_eq!(v, vec![0x04, 0x03, 0x02, 0x01]);
+
+        let mut v = vec![];
+        Address::encode(&Address::udp_loopback(0x1234), &mut v).unwrap();
+        assert_eq!(&v[v.len() - 2..], &[0x34, 0x12]);
+
+        let mut v = vec![];
+        u16::encode(&0x1234, &mut v).unwrap();
+        assert_eq!(v, vec![0xb4, 0x24]);
+        assert_eq!(u16::decode(&v).unwrap().0, 0x1234);
+    }
+
+    fn encode_into_message() -> Message {
+        Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x7070), Address::local(0x00010203)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x04050607)],
+            },
+            message_body: vec![7, 8, 9],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn message_encode_into() {
+        let msg = encode_into_message();
+        let mut expected = vec![];
+        Message::encode(&msg, &mut expected).unwrap();
+        let mut w = std::io::Cursor::new(vec![]);
+        msg.encode_into(&mut w).unwrap();
+        assert_eq!(w.into_inner(), expected);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn message_encode_async() {
+        let msg = encode_into_message();
+        let mut expected = vec![];
+        msg.encode_into(&mut expected).unwrap();
+        let mut w: Vec<u8> = vec![];
+        msg.encode_async(&mut w).await.unwrap();
+        assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn message_decode_versioned() {
+        let msg = encode_into_message();
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        let (m, version) = Message::decode_versioned(&u).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(m.onward_route, msg.onward_route);
+        assert_eq!(m.return_route, msg.return_route);
+        assert_eq!(m.message_body, msg.message_body);
+
+        u[0] = 9;
+        assert_eq!(
+            Message::decode_versioned(&u).unwrap_err(),
+            MessageError::UnsupportedVersion(9)
+        );
+    }
+
+    #[test]
+    fn route_builder() {
+        let route = RouteBuilder::new()
+            .hop(Address::udp_loopback(0x8080))
+            .and_then(|b| b.hop(Address::local(0x00010203)))
+            .unwrap()
+            .build();
+        assert_eq!(
+            route.addresses,
+            vec![Address::udp_loopback(0x8080), Address::local(0x00010203)]
+        );
+
+        let mut builder = RouteBuilder::new();
+        for i in 0..MAX_ROUTE_HOPS {
+            builder = builder.hop(Address::local(i as u32)).unwrap();
+        }
+        assert_eq!(
+            builder.clone().hop(Address::local(0)).unwrap_err(),
+            MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1)
+        );
+        assert_eq!(builder.build().addresses.len(), MAX_ROUTE_HOPS);
+
+        assert_eq!(
+            RouteBuilder::new()
+                .hop(Address::udp_loopback(0))
+                .unwrap_err(),
+            MessageError::InvalidAddress("udp://127.0.0.1:0: port 0".to_string())
+        );
+        let unspecified =
+            Address::UdpAddress(AddressType::Udp, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0x8080);
+        assert!(RouteBuilder::new()
+            .hop(Address::aliased(unspecified, "nowhere"))
+            .is_err());
+    }
+
+    #[test]
+    fn address_is_reachable_from() {
+        let loopback = Address::udp_loopback(0x8080);
+        let other_loopback = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+            0x7070,
+        );
+        let global =
+            Address::UdpAddress(AddressType::Udp, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+        let private_10 = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        );
+        let private_192 = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            0x7070,
+        );
+
+        assert!(loopback.is_reachable_from(&other_loopback));
+        assert!(!loopback.is_reachable_from(&global));
+        assert!(global.is_reachable_from(&loopback));
+        assert!(global.is_reachable_from(&private_10));
+        assert!(!private_10.is_reachable_from(&private_192));
+        assert!(private_10.is_reachable_from(&Address::aliased(private_10.clone(), "lan")));
+        assert!(Address::local(1).is_reachable_from(&global));
+        let tunnel = Address::Tunnel(Box::new(Route {
+            addresses: vec![loopback, global.clone()],
+        }));
+        assert!(!tunnel.is_reachable_from(&global));
+    }
+
+    #[test]
+    fn message_hints() {
+        let mut msg = encode_into_message();
+        let mut without: Vec<u8> = vec![];
+        Message::encode(&msg, &mut without).unwrap();
+
+        msg.hints = vec![(1, vec![b'u', b'd', b'p']), (0x200, vec![])];
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u.len(), msg.encoded_len());
+        assert_eq!(u[1], FLAG_HINTS);
+        // Count, then key 1 with its three byte value, then the two byte key 0x200 with an
+        // empty value
+        let hints = [2, 1, 3, b'u', b'd', b'p', 0x80, 0x04, 0];
+        assert_eq!(u.len(), without.len() + hints.len());
+        let body_start = u.len() - msg.message_body.len();
+        assert_eq!(&u[body_start - hints.len()..body_start], &hints);
+        let (m, body) = Message::decode(&u).unwrap();
+        assert_eq!(m.hints, msg.hints);
+        assert_eq!(m.flags, 0);
+        assert_eq!(m.return_route, msg.return_route);
+        assert_eq!(body, &msg.message_body[..]);
+
+        // No hints take no space at all
+        msg.hints.clear();
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u, without);
+        let (m, _) = Message::decode(&u).unwrap();
+        assert!(m.hints.is_empty());
+    }
+
+    #[test]
+    fn message_truncate_to_mtu() {
+        let mut msg = encode_into_message();
+        msg.message_body = vec![0xab; 100];
+        let len = msg.encoded_len();
+
+        assert!(!msg.truncate_to_mtu(len));
+        assert!(!msg.body_truncated());
+        assert_eq!(msg.message_body.len(), 100);
+
+        assert!(msg.truncate_to_mtu(len - 1));
+        assert!(msg.body_truncated());
+        assert!(msg.message_body.is_empty());
+        assert_eq!(msg.encoded_len(), len - 100);
+
+        // The flag survives the trip to the receiver
+        let mut u: Vec<u8> = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        let (m, _) = Message::decode(&u).unwrap();
+        assert!(m.body_truncated());
+    }
+
+    #[test]
+    fn address_type_min_encoded_len() {
+        assert_eq!(AddressType::Local.min_encoded_len(), 5);
+        assert_eq!(AddressType::Udp.min_encoded_len(), 8);
+        assert_eq!(AddressType::Tcp.min_encoded_len(), 8);
+        for a in [
+            Address::local(1),
+            Address::udp_loopback(0x7070),
+            Address::Tunnel(Box::new(Route { addresses: vec![] })),
+        ]
+        .iter()
+        {
+            let mut v = vec![];
+            Address::encode(a, &mut v).unwrap();
+            let t = AddressType::try_from(v[0]).unwrap();
+            assert_eq!(t.min_encoded_len(), v.len());
+        }
+
+        // An alias of a custom address with no data, ending the buffer
+        let mut registry = AddressCodecRegistry::new();
+        registry
+            .register(0x40, |_, _| Ok(()), |_| Ok((vec![], 0)))
+            .unwrap();
+        let registry = std::sync::Arc::new(registry);
+        let aliased = Address::aliased(Address::Custom(0x40, vec![]), "");
+        let mut v = vec![];
+        Address::encode_with_registry(&aliased, &mut v, &registry).unwrap();
+        assert_eq!(v, vec![AddressType::Aliased as u8, 0x40, 0]);
+        assert_eq!(AddressType::Aliased.min_encoded_len(), v.len());
+        assert_eq!(
+            Address::decode_with_registry(&v, &registry),
+            Ok((aliased, &[][..]))
+        );
+
+        let mut v = vec![];
+        Address::encode(&Address::udp_loopback(0x7070), &mut v).unwrap();
+        assert_eq!(
+            Address::decode(&v[..7]),
+            Err(MessageError::BufferTooShort { needed: 8, got: 7 })
+        );
+        assert_eq!(
+            Address::decode(&[0, 1, 2, 3]),
+            Err(MessageError::BufferTooShort { needed: 5, got: 4 })
+        );
+    }
+
+    #[test]
+    fn message_diff() {
+        let a = Message {
+            return_route: Route {
+                addresses: vec![Address::local(1), Address::udp_loopback(100)],
+            },
+            ..Default::default()
+        };
+        let mut b = Message {
+            return_route: Route {
+                addresses: vec![Address::local(1), Address::udp_loopback(200)],
+            },
+            ..Default::default()
+        };
+        assert_eq!(a.diff(&a), None);
+        assert_eq!(
+            a.diff(&b),
+            Some("return_route[1].port: 100 != 200".to_string())
+        );
+
+        b.return_route.addresses.truncate(1);
+        assert_eq!(a.diff(&b), Some("return_route.len: 2 != 1".to_string()));
+
+        let mut b = Message {
+            return_route: a.return_route.clone(),
+            message_body: vec![0, 1],
+            ..Default::default()
+        };
+        assert_eq!(a.diff(&b), Some("message_body.len: 1 != 2".to_string()));
+        b.set_reply_expected(true);
+        assert_eq!(a.diff(&b), Some("flags: 0x00 != 0x01".to_string()));
+
+        let b = Message {
+            return_route: a.return_route.clone(),
+            ingress: Some(Address::local(2)),
+            ..Default::default()
+        };
+        assert_eq!(
+            a.diff(&b),
+            Some("ingress: None != Some(\"local://00000002\")".to_string())
+        );
+    }
+
+    #[test]
+    fn message_peek_onward_hop_count() {
+        // The frame from message_codec
+        let u = vec![
+            1, 0, 3, 2, 0, 127, 0, 0, 1, 0x80, 0x80, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2, 1, 0,
+            3, 2, 0, 127, 0, 0, 2, 0x80, 0x80, 2, 0, 10, 0, 1, 11, 0x70, 0x70, 0, 3, 2, 1, 0, 0,
+        ];
+        assert_eq!(Message::peek_onward_hop_count(&u), Ok(3));
+        assert_eq!(Message::peek_onward_hop_count(&u[..3]), Ok(3));
+        assert_eq!(
+            Message::peek_onward_hop_count(&u[..2]),
+            Err(MessageError::BufferTooShort { needed: 1, got: 0 })
+        );
+        assert_eq!(
+            Message::peek_onward_hop_count(&[2, 0, 3]),
+            Err(MessageError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn route_extend_from_encoded() {
+        let fragment = Route {
+            addresses: vec![Address::udp_loopback(0x7070), Address::local(0x00010203)],
+        };
+        let mut u = vec![];
+        Route::encode(&fragment, &mut u).unwrap();
+        u.push(0xaa);
+
+        let mut route = Route {
+            addresses: vec![Address::udp_loopback(0x8080)],
+        };
+        assert_eq!(route.extend_from_encoded(&u), Ok(&[0xaa][..]));
+        assert_eq!(
+            route.addresses,
+            vec![
+                Address::udp_loopback(0x8080),
+                Address::udp_loopback(0x7070),
+                Address::local(0x00010203)
+            ]
+        );
+
+        let mut full = Route {
+            addresses: vec![Address::local(0); MAX_ROUTE_HOPS - 1],
+        };
+        assert_eq!(
+            full.extend_from_encoded(&u),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+        assert_eq!(full.addresses.len(), MAX_ROUTE_HOPS - 1);
+    }
+
+    #[test]
+    fn route_varint_count() {
+        for hops in [1, 127, 128, 200].iter() {
+            let route = Route {
+                addresses: (0..*hops).map(|i| Address::local(i as u32)).collect(),
+            };
+            let mut v = vec![];
+            Route::encode(&route, &mut v).unwrap();
+            let count_len = if *hops < 128 { 1 } else { 2 };
+            assert_eq!(v.len(), count_len + hops * 5);
+            assert_eq!(route.encoded_len(), v.len());
+            let (count, _) = u64::decode(&v).unwrap();
+            assert_eq!(count, *hops as u64);
+            let (decoded, rest) = Route::decode(&v).unwrap();
+            assert_eq!(decoded, route);
+            assert!(rest.is_empty());
+            assert_eq!(Route::skip(&v), Ok(&[][..]));
+        }
+
+        let route = Route {
+            addresses: vec![Address::local(0); 200],
+        };
+        let mut v = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        assert_eq!(&v[..2], &[0xc8, 0x01]);
+
+        // Counts past MAX_ROUTE_HOPS are rejected both ways
+        let route = Route {
+            addresses: vec![Address::local(0); MAX_ROUTE_HOPS + 1],
+        };
+        assert_eq!(
+            Route::encode(&route, &mut vec![]),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+        let mut v = vec![];
+        u64::encode(&(MAX_ROUTE_HOPS as u64 + 1), &mut v).unwrap();
+        v.extend_from_slice(&[0; 5 * (MAX_ROUTE_HOPS + 1)]);
+        assert_eq!(
+            Route::decode(&v).err(),
+            Some(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+        assert_eq!(
+            Route::skip(&v),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+    }
+
+    #[test]
+    fn message_validate_routes_reachable() {
+        let loopback = Address::udp_loopback(0x8080);
+        let private = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        );
+        let global =
+            Address::UdpAddress(AddressType::Udp, IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![loopback.clone(), global.clone(), Address::local(1)],
+            },
+            ..Default::default()
+        };
+        assert_eq!(msg.validate_routes_reachable(&loopback), Ok(()));
+        assert_eq!(
+            msg.validate_routes_reachable(&private),
+            Err(MessageError::InvalidAddress(
+                "onward_route[0]: udp://127.0.0.1:32896 isn't reachable from udp://10.0.1.10:28784"
+                    .to_string()
+            ))
+        );
+
+        msg.onward_route.addresses = vec![global.clone(), loopback];
+        assert_eq!(
+            msg.validate_routes_reachable(&global),
+            Err(MessageError::InvalidAddress(
+                "onward_route[1]: udp://127.0.0.1:32896 isn't reachable from udp://8.8.8.8:53"
+                    .to_string()
+            ))
+        );
+
+        msg.onward_route.addresses.clear();
+        assert_eq!(msg.validate_routes_reachable(&global), Ok(()));
+    }
+
+    #[test]
+    fn message_decode_body_limits() {
+        let mut limits = BodyLimits::new();
+        limits.set(ContentType::Json, 16);
+        limits.set(ContentType::Raw, 64);
+        let config = DecodeConfig {
+            body_limits: limits,
+            ..Default::default()
+        };
+
+        let mut json = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            message_body: vec![b'x'; 32],
+            ..Default::default()
+        };
+        json.set_content_type(ContentType::Json);
+        assert_eq!(json.content_type(), Ok(ContentType::Json));
+        let mut v = vec![];
+        Message::encode(&json, &mut v).unwrap();
+        assert_eq!(
+            Message::decode_with_config(&v, &config).err(),
+            Some(MessageError::BodyTooLarge { max: 16, got: 32 })
+        );
+        // Without limits the same frame decodes
+        assert_eq!(Message::decode(&v).unwrap().0.diff(&json), None);
+
+        let mut raw = json;
+        raw.set_content_type(ContentType::Raw);
+        assert_eq!(raw.hints.len(), 1);
+        let mut v = vec![];
+        Message::encode(&raw, &mut v).unwrap();
+        let (decoded, _) = Message::decode_with_config(&v, &config).unwrap();
+        assert_eq!(decoded.diff(&raw), None);
+
+        // Untagged messages are limited as raw
+        raw.hints.clear();
+        raw.message_body = vec![0; 65];
+        let mut v = vec![];
+        Message::encode(&raw, &mut v).unwrap();
+        assert_eq!(
+            Message::decode_with_config(&v, &config).err(),
+            Some(MessageError::BodyTooLarge { max: 64, got: 65 })
+        );
+
+        raw.hints = vec![(HINT_CONTENT_TYPE, vec![9])];
+        let mut v = vec![];
+        Message::encode(&raw, &mut v).unwrap();
+        assert_eq!(
+            Message::decode_with_config(&v, &config).err(),
+            Some(MessageError::UnknownContentType(9))
+        );
+
+        // The other decode entry points apply the limits too
+        raw.hints.clear();
+        let mut v = vec![];
+        Message::encode(&raw, &mut v).unwrap();
+        let too_large = Some(MessageError::BodyTooLarge { max: 64, got: 65 });
+        let mut into = Message::default();
+        assert_eq!(into.decode_into_with_config(&v, &config).err(), too_large);
+        let pool = MessagePool::new(1);
+        assert_eq!(pool.decode_with_config(&v, &config).err(), too_large);
+        let mut batch = vec![];
+        Message::encode_batch(std::slice::from_ref(&raw), &mut batch).unwrap();
+        assert_eq!(
+            Message::decode_batch_with_config(&batch, 1, &config).err(),
+            too_large
+        );
+        let mut stream = MessageStreamDecoder::new();
+        stream.config = config.clone();
+        let mut framed = vec![];
+        Message::encode_length_prefixed(&raw, &mut framed).unwrap();
+        stream.push(&framed);
+        assert_eq!(stream.decode_next().err(), too_large);
+    }
+
+    #[test]
+    fn message_rebase_return() {
+        let mut msg = Message {
+            return_route: Route {
+                addresses: vec![Address::udp_loopback(0x8080), Address::local(1)],
+            },
+            ..Default::default()
+        };
+        let tcp = Address::TcpAddress(
+            AddressType::Tcp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        );
+        msg.rebase_return(tcp.clone());
+        assert_eq!(
+            msg.return_route.addresses,
+            vec![tcp.clone(), Address::local(1)]
+        );
+
+        msg.return_route.addresses.clear();
+        msg.rebase_return(tcp.clone());
+        assert_eq!(msg.return_route.addresses, vec![tcp]);
+    }
+
+    #[test]
+    fn message_body_hash() {
+        let a = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x8080)],
+            },
+            message_body: b"hello".to_vec(),
+            ..Default::default()
+        };
+        let b = Message {
+            return_route: Route {
+                addresses: vec![Address::local(1), Address::local(2)],
+            },
+            message_body: b"hello".to_vec(),
+            flags: FLAG_REPLY_EXPECTED,
+            ..Default::default()
+        };
+        assert_eq!(a.body_hash(), b.body_hash());
+        // Pinned so the hash can't silently change between releases
+        assert_eq!(a.body_hash(), 0xa430_d846_80aa_bd0b);
+
+        let c = Message {
+            message_body: b"hellp".to_vec(),
+            ..Default::default()
+        };
+        assert_ne!(a.body_hash(), c.body_hash());
+        let empty = Message {
+            message_body: vec![],
+            ..Default::default()
+        };
+        assert_eq!(empty.body_hash(), 0xcbf2_9ce4_8422_2325);
+    }
+
+    #[test]
+    fn message_batch_codec() {
+        let msgs: Vec<Message> = (0..3)
+            .map(|i| Message {
+                onward_route: Route {
+                    addresses: vec![Address::local(i)],
+                },
+                message_body: vec![i as u8; i as usize],
+                ..Default::default()
+            })
+            .collect();
+        let mut u = vec![];
+        Message::encode_batch(&msgs, &mut u).unwrap();
+        u.push(0xaa);
+        let (decoded, rest) = Message::decode_batch(&u, 100).unwrap();
+        assert_eq!(decoded.len(), 3);
+        for (d, m) in decoded.iter().zip(msgs.iter()) {
+            assert_eq!(d.diff(m), None);
+        }
+        assert_eq!(rest, &[0xaa]);
+        assert_eq!(
+            Message::decode_batch(&u, 2).err(),
+            Some(MessageError::TooManyMessages(3))
+        );
+
+        // The count is checked before anything else, so only the count needs to be present
+        let mut u = vec![];
+        u64::encode(&1_000_000, &mut u).unwrap();
+        assert_eq!(
+            Message::decode_batch(&u, 100).err(),
+            Some(MessageError::TooManyMessages(1_000_000))
+        );
+    }
+
+    #[test]
+    fn message_size_breakdown() {
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::udp([127, 0, 0, 1], 0x8080),
+                    Address::udp([10, 0, 1, 10], 0x7070),
+                    Address::local(0x00010203),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![
+                    Address::udp([127, 0, 0, 2], 0x8080),
+                    Address::udp([10, 0, 1, 11], 0x7070),
+                    Address::local(0x00010203),
+                ],
+            },
+            ..Default::default()
+        };
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(
+            msg.size_breakdown(),
+            SizeBreakdown {
+                version: 1,
+                flags: 1,
+                ttl: 0,
+                priority: 0,
+                optional_fields: 0,
+                onward_route: 22,
+                return_route: 22,
+                hints: 0,
+                body: 1,
+                total: u.len(),
+            }
+        );
+        assert_eq!(u.len(), 47);
+
+        msg.hints.push((7, vec![1, 2, 3]));
+        let sizes = msg.size_breakdown();
+        assert_eq!(sizes.hints, 1 + 1 + 4);
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(sizes.total, u.len());
+    }
+
+    #[test]
+    fn named_local_address_codec() {
+        assert_codec_roundtrip!(
+            Address,
+            Address::named_local("echo"),
+            vec![4, 4, b'e', b'c', b'h', b'o']
+        );
+        assert_eq!(Address::named_local("echo").to_string(), "worker://echo");
+        assert_eq!(Address::named_local("echo").encoded_len(), 6);
+
+        assert_eq!(
+            Address::decode(&[4, 2, 0xc3, 0x28]),
+            Err(MessageError::InvalidUtf8)
+        );
+        assert_eq!(
+            Address::decode(&[4, 0, 0]).map(|(a, _)| a),
+            Err(MessageError::InvalidAddress(
+                "worker://: empty worker name".to_string()
+            ))
+        );
+
+        let mut v = vec![];
+        assert_eq!(
+            Address::encode(&Address::named_local(""), &mut v),
+            Err(MessageError::InvalidAddress(
+                "worker://: empty worker name".to_string()
+            ))
+        );
+        let long = Address::named_local("x".repeat(MAX_WORKER_NAME_LEN + 1));
+        assert_eq!(
+            Address::encode(&long, &mut v),
+            Err(MessageError::ValueTooLarge)
+        );
+        assert!(v.is_empty());
+
+        let route = Route {
+            addresses: vec![Address::named_local("echo"), Address::local(1)],
+        };
+        assert!(route.is_local_only());
+        let mut v = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        assert_eq!(Route::skip(&v), Ok(&[][..]));
+    }
+
+    #[test]
+    fn message_onward_cost() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::udp_loopback(0x8080),
+                    Address::aliased(Address::local(1), "worker"),
+                    Address::tcp_loopback(0x7070),
+                    Address::named_local("echo"),
+                    Address::Tunnel(Box::new(Route {
+                        addresses: vec![Address::local(2)],
+                    })),
+                ],
+            },
+            ..Default::default()
+        };
+        assert_eq!(msg.onward_cost(&RemoteHopCostModel), 3);
+        assert_eq!(
+            msg.onward_cost(&RemoteHopCostModel),
+            msg.route_summary().remote_hops as u64
+        );
+
+        struct Flat(u32);
+        impl CostModel for Flat {
+            fn hop_cost(&self, _: &Address) -> u32 {
+                self.0
+            }
+        }
+        assert_eq!(msg.onward_cost(&Flat(u32::MAX)), 5 * u32::MAX as u64);
+    }
+
+    #[test]
+    fn message_forward_encode() {
+        let udp = Address::UdpAddress(
+            AddressType::Udp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+            0x7070,
+        );
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x8080), udp.clone()],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            message_body: b"payload".to_vec(),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        msg.forward_encode(&mut out).unwrap();
+        let (decoded, _) = Message::decode(&out).unwrap();
+        assert_eq!(decoded.onward_route.addresses, vec![udp]);
+        assert_eq!(
+            decoded.return_route.addresses,
+            vec![Address::udp_loopback(0x8080), Address::local(1)]
+        );
+        assert_eq!(decoded.message_body, b"payload".to_vec());
+        assert_eq!(decoded.diff(&msg), None);
+
+        // A failed encode leaves the routes as they were
+        msg.return_route
+            .addresses
+            .push(Address::Custom(0x40, vec![]));
+        let mut out = vec![];
+        assert_eq!(
+            msg.forward_encode(&mut out),
+            Err(MessageError::UnknownAddressType(0x40))
+        );
+        assert!(out.is_empty());
+        assert_eq!(msg.onward_route.addresses.len(), 1);
+        assert_eq!(msg.return_route.addresses.len(), 3);
+
+        msg.onward_route.addresses.clear();
+        assert_eq!(msg.forward_encode(&mut out), Err(MessageError::EmptyRoute));
+    }
+
+    #[test]
+    fn route_split_first() {
+        let route = Route {
+            addresses: vec![
+                Address::udp([127, 0, 0, 1], 0x8080),
+                Address::udp([10, 0, 1, 10], 0x7070),
+                Address::local(0x00010203),
+            ],
+        };
+        let (head, tail) = route.split_first().unwrap();
+        assert_eq!(head, Address::udp([127, 0, 0, 1], 0x8080));
+        assert_eq!(
+            tail.addresses,
+            vec![
+                Address::udp([10, 0, 1, 10], 0x7070),
+                Address::local(0x00010203)
+            ]
+        );
+
+        let (head, tail) = tail.split_first().unwrap();
+        assert_eq!(head, Address::udp([10, 0, 1, 10], 0x7070));
+        let (_, tail) = tail.split_first().unwrap();
+        assert!(tail.addresses.is_empty());
+        assert_eq!(tail.split_first(), None);
+    }
+
+    #[test]
+    fn message_stream_decoder_resync() {
+        let msg = |i| Message {
+            onward_route: Route {
+                addresses: vec![Address::local(i)],
+            },
+            message_body: vec![i as u8; 3],
+            ..Default::default()
+        };
+        let mut stream = vec![];
+        Message::encode_length_prefixed(&msg(1), &mut stream).unwrap();
+        // A frame of the right length with unknown flags
+        stream.extend_from_slice(&[6, 1, FLAG_EXTENDED, 0xf0, 1, 0, 0]);
+        Message::encode_length_prefixed(&msg(2), &mut stream).unwrap();
+
+        let mut decoder = MessageStreamDecoder::new();
+        decoder.push(&stream);
+        assert_eq!(decoder.decode_next().unwrap().unwrap().diff(&msg(1)), None);
+        assert_eq!(
+            decoder.decode_next().err(),
+            Some(MessageError::UnknownFlags(0xf0))
+        );
+        decoder.resync();
+        assert_eq!(decoder.decode_next().unwrap().unwrap().diff(&msg(2)), None);
+        assert!(decoder.decode_next().unwrap().is_none());
+        assert_eq!(decoder.buffered(), 0);
+
+        // With skip_bad_frames set, bytes arriving one at a time
+        let mut decoder = MessageStreamDecoder::new();
+        decoder.skip_bad_frames = true;
+        let mut decoded = vec![];
+        for b in stream.iter() {
+            decoder.push(&[*b]);
+            while let Some(m) = decoder.decode_next().unwrap() {
+                decoded.push(m);
+            }
+        }
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].diff(&msg(1)), None);
+        assert_eq!(decoded[1].diff(&msg(2)), None);
+    }
+
+    #[test]
+    fn address_family() {
+        assert_eq!(
+            Address::udp_loopback(0x8080).family(),
+            Some(AddressFamily::V4)
+        );
+        let v6 = Address::TcpAddress(
+            AddressType::Tcp,
+            IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
             0x8080,
+        );
+        assert_eq!(v6.family(), Some(AddressFamily::V6));
+        assert_eq!(
+            Address::aliased(v6, "peer").family(),
+            Some(AddressFamily::V6)
+        );
+        assert_eq!(Address::local(1).family(), None);
+        assert_eq!(Address::named_local("echo").family(), None);
+    }
+
+    #[test]
+    fn empty_message_codec() {
+        let msg = Message {
+            message_body: vec![],
+            ..Default::default()
+        };
+        assert!(msg.is_empty());
+        assert!(!Message::default().is_empty());
+
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_bytes_eq(&u, &[1, 0, 0, 0]);
+        assert_eq!(msg.encoded_len(), 4);
+
+        let (decoded, rest) = Message::decode(&u).unwrap();
+        assert!(decoded.is_empty());
+        assert!(rest.is_empty());
+        assert_eq!(decoded.diff(&msg), None);
+    }
+
+    #[test]
+    fn route_addresses_matching() {
+        let route = Route {
+            addresses: vec![
+                Address::udp([127, 0, 0, 1], 0x8080),
+                Address::udp([8, 8, 8, 8], 53),
+                Address::udp([10, 0, 1, 10], 0x7070),
+                Address::local(1),
+                Address::udp([1, 1, 1, 1], 53),
+            ],
+        };
+        let global: Vec<&Address> = route
+            .addresses_matching(|a| match a {
+                Address::UdpAddress(_, IpAddr::V4(ip), _) => !ip.is_loopback() && !ip.is_private(),
+                _ => false,
+            })
+            .collect();
+        assert_eq!(
+            global,
+            vec![
+                &Address::udp([8, 8, 8, 8], 53),
+                &Address::udp([1, 1, 1, 1], 53)
+            ]
+        );
+
+        let v4 = route.addresses_matching(|a| a.family() == Some(AddressFamily::V4));
+        assert_eq!(v4.count(), 4);
+    }
+
+    #[test]
+    fn message_signable_ranges() {
+        let signed = |u: &[u8]| {
+            let [head, tail] = Message::signable_ranges(u).unwrap();
+            [&u[head], &u[tail]].concat()
+        };
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x8080)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            message_body: b"body".to_vec(),
+            ttl: Some(8),
+            ..Default::default()
+        };
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u[1], FLAG_TTL);
+        assert_eq!(u[2], 8);
+        let [head, tail] = Message::signable_ranges(&u).unwrap();
+        assert_eq!(head, 0..2);
+        assert_eq!(tail, 3..u.len());
+        let onward_len = msg.onward_route.encoded_len();
+        assert_eq!(u[tail.start], 1);
+        assert_eq!(u[tail.start + onward_len], 1);
+        assert!(u[tail].ends_with(b"body"));
+
+        let (decoded, _) = Message::decode(&u).unwrap();
+        assert_eq!(decoded.ttl, Some(8));
+        assert_eq!(decoded.diff(&msg), None);
+        assert_eq!(Message::peek_onward_hop_count(&u), Ok(1));
+
+        // Changing the TTL leaves the signed bytes alone
+        msg.ttl = Some(7);
+        let mut v = vec![];
+        Message::encode(&msg, &mut v).unwrap();
+        assert_ne!(v, u);
+        assert_eq!(signed(&v), signed(&u));
+
+        // But flipping a flag doesn't
+        let mut v = u.clone();
+        v[1] ^= FLAG_REPLY_EXPECTED;
+        assert_ne!(signed(&v), signed(&u));
+
+        // The extended flags byte moves the TTL along and is covered too
+        let fixed = WireFormat {
+            varint_ports: true,
+            fixed_u16: true,
+        };
+        let mut v = vec![];
+        Message::encode_with_format(&msg, &mut v, fixed).unwrap();
+        assert_eq!(v[3], 7);
+        let [head, tail] = Message::signable_ranges(&v).unwrap();
+        assert_eq!(head, 0..3);
+        assert_eq!(tail, 4..v.len());
+
+        msg.ttl = None;
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(
+            Message::signable_ranges(&u),
+            Ok([0..u.len(), u.len()..u.len()])
+        );
+    }
+
+    #[test]
+    fn route_compact() {
+        let udp_a = Address::udp_loopback(0x8080);
+        let udp_b = Address::udp_loopback(0x7070);
+        let local_x = Address::local(1);
+        let mut route = Route {
+            addresses: vec![udp_a.clone(), udp_a.clone(), local_x.clone()],
+        };
+        route.compact();
+        assert_eq!(route.addresses, vec![udp_a.clone(), local_x.clone()]);
+
+        let mut route = Route {
+            addresses: vec![
+                udp_a.clone(),
+                udp_a.clone(),
+                udp_a.clone(),
+                local_x.clone(),
+                local_x.clone(),
+                udp_a.clone(),
+                udp_b.clone(),
+                udp_b.clone(),
+            ],
+        };
+        route.compact();
+        assert_eq!(
+            route.addresses,
+            vec![udp_a.clone(), local_x.clone(), local_x, udp_a, udp_b]
+        );
+    }
+
+    #[test]
+    fn message_priority() {
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            ..Default::default()
+        };
+        assert_eq!(msg.priority(), DEFAULT_PRIORITY);
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u[1], 0);
+
+        msg.set_priority(255);
+        msg.ttl = Some(4);
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u[1], FLAG_TTL | FLAG_PRIORITY);
+        assert_eq!(&u[2..4], &[4, 255]);
+        assert_eq!(msg.encoded_len(), u.len());
+        let (decoded, _) = Message::decode(&u).unwrap();
+        assert_eq!(decoded.priority(), 255);
+        assert_eq!(decoded.diff(&msg), None);
+        assert_eq!(Message::signable_ranges(&u), Ok([0..2, 3..u.len()]));
+
+        let with_priority = |priority| {
+            let mut msg = Message::default();
+            msg.set_priority(priority);
+            ByPriority(msg)
+        };
+        assert!(with_priority(1) > with_priority(0));
+        let mut heap = std::collections::BinaryHeap::new();
+        heap.push(with_priority(3));
+        heap.push(with_priority(200));
+        heap.push(with_priority(0));
+        let order: Vec<u8> = std::iter::from_fn(|| heap.pop().map(|m| m.0.priority())).collect();
+        assert_eq!(order, vec![200, 3, 0]);
+    }
+
+    #[test]
+    fn option_codec() {
+        let la = LocalAddress {
+            address: 0x00010203,
+        };
+        let mut v = vec![];
+        encode_option::<LocalAddress>(&Some(la), &mut v).unwrap();
+        assert_bytes_eq(&v, &[1, 3, 2, 1, 0]);
+        assert_eq!(decode_option::<LocalAddress>(&v), Ok((Some(la), &[][..])));
+
+        let mut v = vec![];
+        encode_option::<LocalAddress>(&None, &mut v).unwrap();
+        assert_bytes_eq(&v, &[0]);
+        v.push(0xaa);
+        assert_eq!(decode_option::<LocalAddress>(&v), Ok((None, &[0xaa][..])));
+
+        assert_eq!(
+            decode_option::<LocalAddress>(&[2, 3, 2, 1, 0]),
+            Err(MessageError::InvalidPresenceByte(2))
+        );
+        assert_eq!(
+            decode_option::<LocalAddress>(&[1, 3, 2]),
+            Err(MessageError::BufferTooShort { needed: 4, got: 2 })
+        );
+        assert_eq!(
+            decode_option::<LocalAddress>(&[]),
+            Err(MessageError::BufferTooShort { needed: 1, got: 0 })
+        );
+    }
+
+    #[test]
+    fn route_to_ipv6_mapped() {
+        let v6 = std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let mut route = Route {
+            addresses: vec![
+                Address::udp([127, 0, 0, 1], 0x8080),
+                Address::udp([10, 0, 1, 10], 0x7070),
+                Address::local(0x00010203),
+                Address::udp(v6, 53),
+                Address::aliased(Address::udp([8, 8, 8, 8], 53), "dns"),
+            ],
+        };
+        route.to_ipv6_mapped();
+        assert_eq!(
+            route.addresses,
+            vec![
+                Address::udp(
+                    "::ffff:127.0.0.1".parse::<std::net::Ipv6Addr>().unwrap(),
+                    0x8080
+                ),
+                Address::udp(
+                    "::ffff:10.0.1.10".parse::<std::net::Ipv6Addr>().unwrap(),
+                    0x7070
+                ),
+                Address::local(0x00010203),
+                Address::udp(v6, 53),
+                Address::aliased(
+                    Address::udp("::ffff:8.8.8.8".parse::<std::net::Ipv6Addr>().unwrap(), 53),
+                    "dns"
+                ),
+            ]
+        );
+
+        let mut v = vec![];
+        Route::encode(&route, &mut v).unwrap();
+        let (decoded, rest) = Route::decode(&v).unwrap();
+        assert_eq!(decoded, route);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn message_decode_body_into() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x8080)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            message_body: b"body bytes".to_vec(),
+            ..Default::default()
+        };
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+
+        let mut body = Vec::with_capacity(64);
+        let buffer = body.as_ptr();
+        let (onward, ret) = Message::decode_body_into(&u, &mut body).unwrap();
+        assert_eq!(onward, msg.onward_route);
+        assert_eq!(ret, msg.return_route);
+        assert_eq!(body, b"body bytes".to_vec());
+        assert_eq!(body.as_ptr(), buffer);
+        assert_eq!(body.capacity(), 64);
+
+        body.clear();
+        assert_eq!(
+            Message::decode_body_into(&u[..3], &mut body),
+            Err(MessageError::RouteTruncated { count: 1, got: 0 })
+        );
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn address_from_str() {
+        let udp: Address = "udp://127.0.0.1:5000".parse().unwrap();
+        assert_eq!(udp, Address::udp_loopback(5000));
+        let tcp: Address = "tcp://[::1]:80".parse().unwrap();
+        assert_eq!(
+            tcp,
+            Address::TcpAddress(
+                AddressType::Tcp,
+                IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+                80
+            )
+        );
+        for a in [
+            Address::udp_loopback(0x8080),
+            Address::local(0x00010203),
+            Address::named_local("echo"),
+        ]
+        .iter()
+        {
+            assert_eq!(a.to_string().parse::<Address>().as_ref(), Ok(a));
+        }
+
+        assert_eq!(
+            "udp://127.0.0.1".parse::<Address>(),
+            Err(MessageError::InvalidAddress(
+                "udp://127.0.0.1: missing port".to_string()
+            ))
+        );
+        assert_eq!(
+            "local://123456789".parse::<Address>(),
+            Err(MessageError::InvalidAddress(
+                "local://123456789: bad local address".to_string()
+            ))
+        );
+        assert_eq!(
+            "ftp://1.2.3.4:21".parse::<Address>(),
+            Err(MessageError::InvalidAddress(
+                "ftp://1.2.3.4:21: unsupported scheme".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn address_parse_with_resolver() {
+        struct MockResolver;
+        impl InterfaceResolver for MockResolver {
+            fn resolve(&self, interface: &str) -> Option<IpAddr> {
+                match interface {
+                    "eth0" => Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5))),
+                    _ => None,
+                }
+            }
+        }
+
+        assert_eq!(
+            Address::parse_with_resolver("udp://eth0:5000", Some(&MockResolver)),
+            Ok(Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5)),
+                5000
+            ))
+        );
+        // Literal IPs don't go through the resolver
+        assert_eq!(
+            Address::parse_with_resolver("udp://10.0.0.1:53", Some(&MockResolver)),
+            Ok(Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                53
+            ))
+        );
+        let unknown = Err(MessageError::InvalidAddress(
+            "udp://eth1:5000: not an IP address or known interface".to_string(),
+        ));
+        assert_eq!(
+            Address::parse_with_resolver("udp://eth1:5000", Some(&MockResolver)),
+            unknown
+        );
+        assert_eq!(
+            Address::parse_with_resolver("udp://eth0:5000", None),
+            Err(MessageError::InvalidAddress(
+                "udp://eth0:5000: not an IP address or known interface".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn message_encode_is_deterministic() {
+        let ip_a = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let ip_b = IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10));
+        let encode = |msg: &Message| {
+            let mut u = vec![];
+            Message::encode(msg, &mut u).unwrap();
+            u
+        };
+
+        let literal = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::UdpAddress(AddressType::Udp, ip_a, 0x8080),
+                    Address::UdpAddress(AddressType::Udp, ip_b, 0x7070),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(0x00010203)],
+            },
+            message_body: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let built = Message {
+            onward_route: RouteBuilder::new()
+                .hop(Address::udp_loopback(0x8080))
+                .unwrap()
+                .hop("udp://10.0.1.10:28784".parse().unwrap())
+                .unwrap()
+                .build(),
+            return_route: RouteBuilder::new()
+                .hop("local://00010203".parse().unwrap())
+                .unwrap()
+                .build(),
+            message_body: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let mut pushed = Message {
+            onward_route: Route::from_udp_endpoints(vec![(ip_b, 0x7070)]),
+            message_body: vec![],
+            ..Default::default()
+        };
+        pushed
+            .push_onward_front(Address::UdpAddress(AddressType::Udp, ip_a, 0x8080))
+            .unwrap();
+        pushed
+            .prepend_return_hop(Address::local(0x00010203))
+            .unwrap();
+        pushed.message_body.extend_from_slice(&[1, 2, 3]);
+
+        let tuples = Message {
+            onward_route: Route::from_udp_endpoints(vec![(ip_a, 0x8080), (ip_b, 0x7070)]),
+            return_route: Route {
+                addresses: vec![Address::local(0x00010203)],
+            },
+            message_body: vec![1, 2, 3],
+            ..Default::default()
+        };
+
+        let expected = encode(&literal);
+        for msg in [built, pushed, tuples].iter() {
+            assert_bytes_eq(&encode(msg), &expected);
+        }
+    }
+
+    #[test]
+    fn route_dial_order() {
+        let udp = Address::udp_loopback(0x8080);
+        let tcp = Address::tcp_loopback(0x8080);
+        let tunnel = Address::Tunnel(Box::new(Route {
+            addresses: vec![Address::local(2)],
+        }));
+        let route = Route {
+            addresses: vec![udp.clone(), Address::local(1), tunnel.clone(), tcp.clone()],
+        };
+        assert_eq!(
+            route.dial_order(&[AddressType::Tcp, AddressType::Udp]),
+            vec![&tcp, &udp, &tunnel]
+        );
+        assert_eq!(
+            route.dial_order(&[AddressType::Udp, AddressType::Tcp]),
+            vec![&udp, &tcp, &tunnel]
+        );
+        assert_eq!(route.dial_order(&[]), vec![&udp, &tunnel, &tcp]);
+    }
+
+    #[test]
+    fn route_decode_empty_boundary() {
+        let (route, rest) = Route::decode(&[0]).unwrap();
+        assert!(route.addresses.is_empty());
+        assert!(rest.is_empty());
+
+        let (route, rest) = Route::decode(&[0, 0xaa, 0xbb]).unwrap();
+        assert!(route.addresses.is_empty());
+        assert_eq!(rest, &[0xaa, 0xbb]);
+        assert_eq!(Route::skip(&[0, 0xaa]), Ok(&[0xaa][..]));
+
+        let too_short = MessageError::BufferTooShort { needed: 1, got: 0 };
+        assert_eq!(Route::decode(&[]), Err(too_short.clone()));
+        assert_eq!(Route::skip(&[]), Err(too_short));
+    }
+
+    #[test]
+    fn message_map_body() {
+        let onward_route = Route {
+            addresses: vec![Address::udp_loopback(0x8080), Address::local(1)],
+        };
+        let msg = Message {
+            onward_route: onward_route.clone(),
+            message_body: vec![1, 2, 3],
+            flags: FLAG_REPLY_EXPECTED,
+            ..Default::default()
+        };
+        let msg = msg
+            .map_body(|mut body| {
+                body.reverse();
+                Ok(body)
+            })
+            .unwrap();
+        assert_eq!(msg.onward_route, onward_route);
+        assert!(msg.reply_expected());
+        assert_eq!(msg.message_body, vec![3, 2, 1]);
+
+        assert_eq!(
+            msg.map_body(|_| Err(MessageError::ValueTooLarge)).err(),
+            Some(MessageError::ValueTooLarge)
+        );
+    }
+
+    #[test]
+    fn body_only_message_codec() {
+        assert_codec_roundtrip!(
+            BodyOnlyMessage,
+            BodyOnlyMessage(vec![1, 2, 3]),
+            vec![BODY_ONLY_MARKER, 3, 1, 2, 3]
+        );
+        assert_codec_roundtrip!(
+            BodyOnlyMessage,
+            BodyOnlyMessage(vec![]),
+            vec![BODY_ONLY_MARKER, 0]
+        );
+
+        // Neither format is mistaken for the other
+        let mut v = vec![];
+        BodyOnlyMessage::encode(&BodyOnlyMessage(vec![1]), &mut v).unwrap();
+        assert!(matches!(
+            Message::decode(&v),
+            Err(MessageError::UnsupportedVersion(_))
+        ));
+        let mut v = vec![];
+        Message::encode(&Message::default(), &mut v).unwrap();
+        assert_eq!(BodyOnlyMessage::decode(&v), Err(MessageError::NotBodyOnly));
+
+        let msg = Message::from(BodyOnlyMessage(vec![1, 2, 3]));
+        assert!(msg.onward_route.addresses.is_empty());
+        assert!(msg.return_route.addresses.is_empty());
+        assert_eq!(msg.message_body, vec![1, 2, 3]);
+        assert_eq!(
+            BodyOnlyMessage::try_from(msg),
+            Ok(BodyOnlyMessage(vec![1, 2, 3]))
+        );
+
+        let mut routed = Message::default();
+        routed.onward_route.addresses.push(Address::local(1));
+        assert_eq!(
+            BodyOnlyMessage::try_from(routed),
+            Err(MessageError::NotBodyOnly)
+        );
+    }
+
+    #[test]
+    fn u16_decode_reconstruction() {
+        assert_eq!(u16::decode(&[0xff, 0xff]), Ok((0x7fff, &[][..])));
+        assert_eq!(u16::decode(&[0xff, 0x01]), Ok((0xff, &[][..])));
+        assert_eq!(u16::decode(&[0x80, 0xfe, 0xaa]), Ok((0x7f00, &[0xaa][..])));
+
+        for value in 0..0x8000u16 {
+            let mut v = vec![];
+            u16::encode(&value, &mut v).unwrap();
+            assert_eq!(u16::decode(&v), Ok((value, &[][..])));
+        }
+    }
+
+    #[test]
+    fn message_tenant() {
+        let tagged = |tenant| Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            tenant,
+            ..Default::default()
+        };
+
+        let msg = tagged(Some(0x01020304));
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(
+            &u[1..9],
+            &[FLAG_EXTENDED, EXT_FLAG_OPTIONAL_FIELDS, 0, 1, 4, 3, 2, 1]
+        );
+        assert_eq!(msg.encoded_len(), u.len());
+        assert_eq!(
+            Message::signable_ranges(&u),
+            Ok([0..u.len(), u.len()..u.len()])
+        );
+        let (decoded, _) = Message::decode(&u).unwrap();
+        assert_eq!(decoded.tenant(), Some(0x01020304));
+        assert_eq!(decoded.diff(&msg), None);
+
+        let msg = tagged(None);
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(u[1], 0);
+        assert_eq!(Message::decode(&u).unwrap().0.tenant(), None);
+        // The bit the tenant flag had is free again
+        assert_eq!(
+            Message::decode(&[1, 0x40, 0, 0]).err(),
+            Some(MessageError::UnknownFlags(0x40))
+        );
+
+        let msgs = [
+            tagged(Some(7)),
+            tagged(None),
+            tagged(Some(8)),
+            tagged(Some(7)),
+        ];
+        let filter = TenantFilter::new(vec![7]);
+        let tenant_7: Vec<&Message> = msgs.iter().filter(|m| filter.accepts(m)).collect();
+        assert_eq!(tenant_7.len(), 2);
+        assert!(tenant_7.iter().all(|m| m.tenant() == Some(7)));
+
+        let filter = TenantFilter::new(vec![7, 8]);
+        assert_eq!(msgs.iter().filter(|m| filter.accepts(m)).count(), 3);
+        assert!(!TenantFilter::new(vec![]).accepts(&msgs[1]));
+    }
+
+    #[test]
+    fn message_return_matches_reversed_onward() {
+        let tcp = Address::TcpAddress(
+            AddressType::Tcp,
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            4000,
+        );
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![tcp.clone(), Address::local(1)],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(1), tcp.clone()],
+            },
+            ..Default::default()
+        };
+        assert!(msg.return_matches_reversed_onward());
+
+        msg.return_route.addresses = vec![tcp, Address::local(1)];
+        assert!(!msg.return_matches_reversed_onward());
+    }
+
+    #[test]
+    fn route_decode_from_iter() {
+        let route = Route {
+            addresses: vec![
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    0x8080,
+                ),
+                Address::local(0x00010203),
+            ],
+        };
+        let mut u = vec![];
+        Route::encode(&route, &mut u).unwrap();
+        u.push(0xaa);
+
+        let mut bytes = u.clone().into_iter();
+        let decoded = Route::decode_from_iter(bytes.by_ref()).unwrap();
+        assert_eq!(decoded, route);
+        // Only the route was consumed
+        assert_eq!(bytes.collect::<Vec<u8>>(), vec![0xaa]);
+
+        let truncated = u[..u.len() - 3].to_vec();
+        assert!(matches!(
+            Route::decode_from_iter(truncated.into_iter()),
+            Err(MessageError::BufferTooShort { .. })
         ));
-        return_addresses.push(Address::UdpAddress(
-            AddressType::Udp,
-            IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
-            0x7070,
+        assert!(matches!(
+            Route::decode_from_iter(std::iter::empty()),
+            Err(MessageError::BufferTooShort { needed: 1, got: 0 })
         ));
-        return_addresses.push(Address::LocalAddress(
-            AddressType::Local,
-            LocalAddress {
-                address: 0x00010203,
+    }
+
+    #[test]
+    fn message_text_form() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::UdpAddress(
+                        AddressType::Udp,
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                        4000,
+                    ),
+                    Address::TcpAddress(
+                        AddressType::Tcp,
+                        IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+                        80,
+                    ),
+                    Address::named_local("echo"),
+                ],
+            },
+            return_route: Route {
+                addresses: vec![Address::local(1)],
             },
+            message_body: vec![0x00, 0xab, 0xff],
+            flags: FLAG_REPLY_EXPECTED,
+            hints: vec![(1, vec![2])],
+            ttl: Some(8),
+            priority: 200,
+            tenant: Some(7),
+            nonce: None,
+            affinity: Some(42),
+            ingress: None,
+        };
+        let text = msg.encode_text();
+        assert_eq!(
+            text,
+            "onward=[udp://127.0.0.1:4000, tcp://[::1]:80, worker://echo] \
+             return=[local://00000001] flags=01 ttl=8 priority=200 tenant=7 affinity=42 \
+             hint=1:02 body=00abff"
+        );
+        assert!(!text.contains('\n'));
+        let decoded = Message::decode_text(&text).unwrap();
+        assert_eq!(decoded.diff(&msg), None);
+
+        let plain = Message::default();
+        assert_eq!(plain.encode_text(), "onward=[] return=[] body=00");
+        assert_eq!(
+            Message::decode_text(&plain.encode_text())
+                .unwrap()
+                .diff(&plain),
+            None
+        );
+
+        assert!(matches!(
+            Message::decode_text("onward=[] body=0"),
+            Err(MessageError::InvalidText(_))
         ));
-        let onward_route = Route {
-            addresses: onward_addresses,
+
+        // Worker names holding the separators are escaped so they read back
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![
+                    Address::named_local("a, b"),
+                    Address::named_local("c]"),
+                    Address::named_local("100%\n"),
+                    Address::local(2),
+                ],
+            },
+            ..Default::default()
         };
-        let return_route = Route {
-            addresses: return_addresses,
+        let text = msg.encode_text();
+        assert_eq!(
+            text,
+            "onward=[worker://a%2c%20b, worker://c%5d, worker://100%25%0a, local://00000002] \
+             return=[] body=00"
+        );
+        assert_eq!(Message::decode_text(&text).unwrap().diff(&msg), None);
+        assert!(matches!(
+            Message::decode_text("onward=[worker://a%2] body=00"),
+            Err(MessageError::InvalidText(_))
+        ));
+        assert_eq!(
+            MessageError::InvalidText("x".to_string()).to_string(),
+            "Invalid message text: x"
+        );
+    }
+
+    #[test]
+    fn message_affinity() {
+        let keyed = |affinity| Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            affinity,
+            ..Default::default()
+        };
+
+        let msg = keyed(Some(300));
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(
+            &u[1..8],
+            [FLAG_EXTENDED, EXT_FLAG_OPTIONAL_FIELDS, 0, 0, 1, 0xac, 0x02]
+        );
+        let (decoded, _) = Message::decode(&u).unwrap();
+        assert_eq!(decoded.affinity(), Some(300));
+        assert_eq!(decoded.diff(&msg), None);
+
+        let mut u = vec![];
+        Message::encode(&keyed(None), &mut u).unwrap();
+        assert_eq!(u[1], 0);
+        assert_eq!(Message::decode(&u).unwrap().0.affinity(), None);
+
+        // A balancer that hashes the affinity key to one of its backends
+        let backend = |m: &Message| {
+            m.affinity().map(|a| {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                std::hash::Hash::hash(&a, &mut hasher);
+                std::hash::Hasher::finish(&hasher) % 8
+            })
+        };
+        let a = keyed(Some(0xdead_beef));
+        let mut b = keyed(Some(0xdead_beef));
+        b.message_body = vec![1, 2, 3];
+        b.onward_route.addresses.push(Address::local(2));
+        assert!(backend(&a).is_some());
+        assert_eq!(backend(&a), backend(&b));
+        assert_eq!(backend(&keyed(None)), None);
+    }
+
+    #[test]
+    fn message_decode_cow() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            message_body: vec![1, 2, 3, 4],
+            ..Default::default()
+        };
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+
+        let cow = Message::decode_cow(&u).unwrap();
+        let body_start = u.len() - 4;
+        match &cow.body {
+            std::borrow::Cow::Borrowed(body) => assert_eq!(body.as_ptr(), u[body_start..].as_ptr()),
+            std::borrow::Cow::Owned(_) => panic!("the body was copied"),
+        }
+        assert_eq!(cow.header.message_body.capacity(), 0);
+        assert_eq!(cow.header.onward_route, msg.onward_route);
+
+        let owned = cow.into_owned();
+        assert_ne!(owned.message_body.as_ptr(), u[body_start..].as_ptr());
+        u.iter_mut().for_each(|b| *b = 0);
+        assert_eq!(owned.diff(&msg), None);
+
+        assert!(Message::decode_cow(&[]).is_err());
+    }
+
+    #[test]
+    fn route_standalone_bytes() {
+        let route = Route {
+            addresses: vec![
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    0x8080,
+                ),
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
+                    0x7070,
+                ),
+                Address::local(0x00010203),
+            ],
+        };
+        let mut u = route.encode_to_vec().unwrap();
+        assert_eq!(u.len(), route.encoded_len());
+        assert_eq!(Route::from_bytes(&u), Ok(route));
+
+        u.extend_from_slice(&[0, 0]);
+        assert_eq!(Route::from_bytes(&u), Err(MessageError::TrailingBytes(2)));
+    }
+
+    // Only the prelude is in scope here, to show it is enough on its own
+    mod prelude_only {
+        use crate::prelude::*;
+
+        #[test]
+        fn prelude_roundtrip() {
+            let msg = Message {
+                onward_route: Route {
+                    addresses: vec![Address::LocalAddress(
+                        AddressType::Local,
+                        LocalAddress { address: 7 },
+                    )],
+                },
+                message_body: vec![1, 2, 3],
+                ..Default::default()
+            };
+            let mut u = vec![];
+            Message::encode(&msg, &mut u).unwrap();
+            let (decoded, rest) = Message::decode(&u).unwrap();
+            assert!(rest.ends_with(&[1, 2, 3]));
+            assert_eq!(decoded.diff(&msg), None);
+            let result: Result<(Message, &[u8]), MessageError> = Message::decode(&[]);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn address_from_parts() {
+        assert_eq!(
+            Address::from_parts(AddressType::Udp, &[127, 0, 0, 1], 4000),
+            Ok(Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                4000
+            ))
+        );
+        let mut v6 = [0u8; 16];
+        v6[15] = 1;
+        assert_eq!(
+            Address::from_parts(AddressType::Tcp, &v6, 80),
+            Ok(Address::TcpAddress(
+                AddressType::Tcp,
+                IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+                80
+            ))
+        );
+        assert!(matches!(
+            Address::from_parts(AddressType::Udp, &[127, 0, 0], 4000),
+            Err(MessageError::InvalidAddress(_))
+        ));
+        assert!(matches!(
+            Address::from_parts(AddressType::Local, &[127, 0, 0, 1], 4000),
+            Err(MessageError::InvalidAddress(_))
+        ));
+    }
+
+    #[test]
+    fn route_count_checked_upfront() {
+        // Ten addresses declared, but only six bytes of address data follow
+        let mut u = vec![10];
+        Address::encode(&Address::local(1), &mut u).unwrap();
+        u.push(0);
+        assert_eq!(u.len(), 1 + 6);
+        let truncated = Err(MessageError::RouteTruncated { count: 10, got: 6 });
+        assert_eq!(Route::decode(&u).map(|(r, _)| r), truncated);
+        assert_eq!(Route::skip(&u).map(|_| ()), truncated.map(|_: Route| ()));
+
+        // Two addresses can't fit in five bytes, as every built-in address takes at least
+        // three, but custom ones a registry decodes may take one
+        let u = [2, 4, 1, b'a', 0, 0];
+        let truncated = Err(MessageError::RouteTruncated { count: 2, got: 5 });
+        assert_eq!(Route::decode(&u).map(|(r, _)| r), truncated);
+        assert_eq!(Route::skip(&u).map(|_| ()), truncated.map(|_: Route| ()));
+        let mut registry = AddressCodecRegistry::new();
+        registry
+            .register(0x40, |_, _| Ok(()), |_| Ok((vec![], 0)))
+            .unwrap();
+        assert_eq!(
+            Route::skip_with_registry(&[2, 0x40, 0x40], &registry),
+            Ok(&[][..])
+        );
+    }
+
+    #[test]
+    fn message_reply() {
+        let mut request = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x1010), Address::local(7)],
+            },
+            return_route: Route {
+                addresses: vec![Address::udp_loopback(0x2020), Address::local(9)],
+            },
+            message_body: b"ping".to_vec(),
+            tenant: Some(3),
+            ..Default::default()
+        };
+        request.set_reply_expected(true);
+
+        let reply = request.reply(b"pong".to_vec());
+        assert_eq!(reply.onward_route, request.return_route);
+        assert!(reply.return_route.addresses.is_empty());
+        assert_eq!(reply.message_body, b"pong".to_vec());
+        assert_eq!(reply.tenant(), Some(3));
+        assert!(!reply.reply_expected());
+    }
+
+    #[cfg(feature = "mac")]
+    #[test]
+    fn message_mac() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x1010), Address::local(7)],
+            },
+            message_body: b"payload".to_vec(),
+            ..Default::default()
+        };
+        let mut u = vec![];
+        msg.encode_mac(b"shared key", &mut u).unwrap();
+        assert_eq!(u.len(), msg.encoded_len() + MAC_LEN);
+        let decoded = Message::decode_mac(&u, b"shared key").unwrap();
+        assert_eq!(decoded.diff(&msg), None);
+
+        assert_eq!(
+            Message::decode_mac(&u, b"wrong key").err(),
+            Some(MessageError::MacMismatch)
+        );
+
+        let last_body_byte = u.len() - MAC_LEN - 1;
+        u[last_body_byte] ^= 1;
+        assert_eq!(
+            Message::decode_mac(&u, b"shared key").err(),
+            Some(MessageError::MacMismatch)
+        );
+    }
+
+    #[test]
+    fn route_longest_reachable_prefix() {
+        let private = |last, port| {
+            Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)),
+                port,
+            )
         };
-        let mut message_body = vec![0];
+        let local = private(1, 4000);
+        let route = Route {
+            addresses: vec![
+                private(2, 4000),
+                private(3, 4000),
+                // Loopback isn't reachable from the private network
+                Address::udp_loopback(4000),
+                Address::local(7),
+            ],
+        };
+        let prefix = route.longest_reachable_prefix(&local);
+        assert_eq!(prefix.addresses, route.addresses[..2].to_vec());
+
+        assert_eq!(
+            Route { addresses: vec![] }.longest_reachable_prefix(&local),
+            Route { addresses: vec![] }
+        );
+    }
+
+    #[test]
+    fn local_address_decode_nonzero() {
+        let zero = [0u8, 0, 0, 0, 0xaa];
+        assert_eq!(
+            LocalAddress::decode_nonzero(&zero).err(),
+            Some(MessageError::NullLocalAddress)
+        );
+        // The permissive decoder still accepts it
+        assert!(LocalAddress::decode(&zero).unwrap().0.is_null());
+
+        let (la, rest) = LocalAddress::decode_nonzero(&[3, 2, 1, 0, 0xaa]).unwrap();
+        assert_eq!(la.address, 0x00010203);
+        assert_eq!(rest, &[0xaa]);
+        assert_eq!(
+            LocalAddress::decode_nonzero(&[1, 0]).err(),
+            Some(MessageError::BufferTooShort { needed: 4, got: 2 })
+        );
+    }
+
+    #[test]
+    fn message_ingress() {
+        let ingress = Address::udp_loopback(4050);
         let mut msg = Message {
-            onward_route,
-            return_route,
-            message_body,
+            onward_route: Route {
+                addresses: vec![Address::local(7)],
+            },
+            return_route: Route {
+                addresses: vec![Address::udp_loopback(0x2020), Address::local(9)],
+            },
+            ..Default::default()
         };
-        let mut u: Vec<u8> = vec![];
-        Message::encode(&mut msg, &mut u);
+        assert_eq!(msg.to_reply_route(), msg.return_route);
+
+        msg.set_ingress(ingress.clone());
+        assert_eq!(msg.ingress(), Some(&ingress));
+        let reply_route = msg.to_reply_route();
+        assert_eq!(reply_route.addresses[0], ingress);
+        assert_eq!(reply_route.addresses[1..], msg.return_route.addresses[..]);
+        assert_eq!(msg.reply(vec![]).onward_route, reply_route);
+
+        // The ingress stays off the wire
+        let mut with_ingress = vec![];
+        Message::encode(&msg, &mut with_ingress).unwrap();
+        msg.ingress = None;
+        let mut without = vec![];
+        Message::encode(&msg, &mut without).unwrap();
+        assert_eq!(with_ingress, without);
+        assert_eq!(Message::decode(&with_ingress).unwrap().0.ingress(), None);
+    }
+
+    #[test]
+    fn fixed_u16_format() {
+        let mut u = vec![];
+        FixedU16::encode(&0x1300, &mut u).unwrap();
+        assert_eq!(u, vec![0x00, 0x13]);
+        assert_eq!(FixedU16::decode(&u), Ok((0x1300, &[][..])));
+        // Values the varint can't encode
+        let mut u = vec![];
+        FixedU16::encode(&0xffff, &mut u).unwrap();
+        assert_eq!(FixedU16::decode(&u), Ok((0xffff, &[][..])));
+        assert_eq!(
+            FixedU16::decode(&[1]),
+            Err(MessageError::BufferTooShort { needed: 2, got: 1 })
+        );
+
+        let fixed = WireFormat {
+            varint_ports: true,
+            fixed_u16: true,
+        };
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::udp_loopback(0x1300)],
+            },
+            hints: vec![(0x1300, vec![7]), (0x8000, vec![])],
+            ..Default::default()
+        };
+        let mut u = vec![];
+        Message::encode_with_format(&msg, &mut u, fixed).unwrap();
         assert_eq!(
             u,
             vec![
-                3, 2, 0, 127, 0, 0, 1, 0x80, 0x80, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2, 1, 0,
-                3, 2, 0, 127, 0, 0, 2, 0x80, 0x80, 2, 0, 10, 0, 1, 11, 0x70, 0x70, 0, 3, 2, 1, 0,
+                1,
+                FLAG_HINTS | FLAG_EXTENDED,
+                EXT_FLAG_FIXED_U16,
+                1,
+                2,
+                0,
+                127,
+                0,
+                0,
+                1,
+                0x00,
+                0x13,
+                0,
+                2,
+                0x00,
+                0x13,
+                1,
+                7,
+                0x00,
+                0x80,
+                0,
                 0
             ]
         );
+        let (decoded, _) = Message::decode(&u).unwrap();
+        assert_eq!(decoded.diff(&msg), None);
+        assert_eq!(decoded.flags, 0);
+    }
 
-        match Message::decode(&u) {
-            Ok((m, v)) => {
-                assert_eq!(m.onward_route.addresses.len(), 3);
-                assert_eq!(
-                    m.onward_route.addresses[0],
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                        0x8080
-                    )
-                );
-                assert_eq!(
-                    m.onward_route.addresses[1],
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(10, 0, 1, 10)),
-                        0x7070
-                    )
-                );
-                assert_eq!(
-                    m.onward_route.addresses[2],
-                    Address::LocalAddress(
-                        AddressType::Local,
-                        LocalAddress {
-                            address: 0x00010203
-                        }
-                    )
-                );
-                assert_eq!(m.return_route.addresses.len(), 3);
-                assert_eq!(
-                    m.return_route.addresses[0],
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
-                        0x8080
-                    )
-                );
-                assert_eq!(
-                    m.return_route.addresses[1],
-                    Address::UdpAddress(
-                        AddressType::Udp,
-                        IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
-                        0x7070
-                    )
-                );
-                assert_eq!(
-                    m.return_route.addresses[2],
-                    Address::LocalAddress(
-                        AddressType::Local,
-                        LocalAddress {
-                            address: 0x00010203
-                        }
-                    )
-                );
-                assert_eq!(m.message_body[0], 0);
-            }
-            Err(e) => panic!(),
+    #[test]
+    fn route_table_resolve() {
+        let full = Route {
+            addresses: vec![
+                Address::udp_loopback(0x1010),
+                Address::udp_loopback(0x2020),
+                Address::local(7),
+            ],
+        };
+        let mut table = RouteTable::new();
+        assert_eq!(table.insert(Address::local(7), full.clone()), None);
+        assert_eq!(table.lookup(&Address::local(7)), Some(&full));
+
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(7)],
+            },
+            ..Default::default()
+        };
+        assert!(table.resolve(&mut msg));
+        assert_eq!(msg.onward_route, full);
+        // Already a full route, so there's nothing to resolve
+        assert!(!table.resolve(&mut msg));
+
+        let mut unknown = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(8)],
+            },
+            ..Default::default()
+        };
+        assert!(!table.resolve(&mut unknown));
+        assert_eq!(unknown.onward_route.addresses, vec![Address::local(8)]);
+
+        assert_eq!(table.remove(&Address::local(7)), Some(full));
+        assert_eq!(table.lookup(&Address::local(7)), None);
+    }
+
+    #[test]
+    fn message_encoded_len_upper_bound() {
+        let v6 = |port| {
+            Address::UdpAddress(
+                AddressType::Udp,
+                IpAddr::V6(std::net::Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1)),
+                port,
+            )
+        };
+        let mut tagged = Message {
+            onward_route: Route {
+                addresses: vec![v6(0x8080), Address::local(1)],
+            },
+            return_route: Route {
+                addresses: vec![v6(1), v6(2), Address::named_local("echo")],
+            },
+            message_body: vec![0; 100],
+            hints: vec![(0x1300, vec![1, 2, 3])],
+            ttl: Some(4),
+            priority: 0,
+            tenant: Some(9),
+            ..Default::default()
+        };
+        tagged.set_reply_expected(true);
+        let messages = [
+            Message::default(),
+            Message {
+                onward_route: Route {
+                    addresses: vec![v6(4000); 8],
+                },
+                ..Default::default()
+            },
+            Message {
+                onward_route: Route {
+                    addresses: vec![Address::Tunnel(Box::new(Route {
+                        addresses: vec![v6(1), Address::local(2)],
+                    }))],
+                },
+                ..Default::default()
+            },
+            tagged,
+        ];
+        for msg in messages.iter() {
+            let bound = msg.encoded_len_upper_bound();
+            assert!(bound >= msg.encoded_len());
+            let mut u = vec![];
+            Message::encode_with_format(
+                msg,
+                &mut u,
+                WireFormat {
+                    fixed_u16: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert!(bound >= u.len());
+        }
+    }
+
+    #[test]
+    fn message_nonce() {
+        let msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::local(1)],
+            },
+            nonce: Some(300),
+            ..Default::default()
+        };
+        let mut u = vec![];
+        Message::encode(&msg, &mut u).unwrap();
+        assert_eq!(
+            &u[1..6],
+            &[FLAG_EXTENDED, EXT_FLAG_OPTIONAL_FIELDS, 1, 0xac, 0x02]
+        );
+        assert_eq!(msg.encoded_len(), u.len());
+        assert_eq!(
+            Message::signable_ranges(&u),
+            Ok([0..u.len(), u.len()..u.len()])
+        );
+        let (decoded, _) = Message::decode(&u).unwrap();
+        assert_eq!(decoded.nonce(), Some(300));
+        assert_eq!(decoded.diff(&msg), None);
+
+        u[3] = 2;
+        assert_eq!(
+            Message::decode(&u).err(),
+            Some(MessageError::InvalidPresenceByte(2))
+        );
+    }
+
+    #[test]
+    fn replay_window() {
+        let with_nonce = |nonce| Message {
+            nonce,
+            ..Default::default()
+        };
+        let mut window = ReplayWindow::new();
+        assert_eq!(window.check_and_record(&with_nonce(Some(100))), Ok(()));
+        assert_eq!(
+            window.check_and_record(&with_nonce(Some(100))),
+            Err(MessageError::ReplayedNonce(100))
+        );
+        // Out of order but within the window
+        assert_eq!(window.check_and_record(&with_nonce(Some(102))), Ok(()));
+        assert_eq!(window.check_and_record(&with_nonce(Some(101))), Ok(()));
+        assert_eq!(
+            window.check_and_record(&with_nonce(Some(101))),
+            Err(MessageError::ReplayedNonce(101))
+        );
+
+        assert_eq!(window.check_and_record(&with_nonce(Some(200))), Ok(()));
+        assert_eq!(
+            window.check_and_record(&with_nonce(Some(200 - REPLAY_WINDOW_LEN))),
+            Err(MessageError::StaleNonce(200 - REPLAY_WINDOW_LEN))
+        );
+        assert_eq!(
+            window.check_and_record(&with_nonce(Some(102))),
+            Err(MessageError::StaleNonce(102))
+        );
+        assert_eq!(
+            window.check_and_record(&with_nonce(Some(200 - REPLAY_WINDOW_LEN + 1))),
+            Ok(())
+        );
+        assert_eq!(
+            window.check_and_record(&with_nonce(None)),
+            Err(MessageError::MissingNonce)
+        );
+    }
+
+    #[test]
+    fn message_decode_return_route() {
+        // The frame from message_codec
+        let u = [
+            1, 0, 3, 2, 0, 127, 0, 0, 1, 0x80, 0x80, 2, 0, 10, 0, 1, 10, 0x70, 0x70, 0, 3, 2, 1, 0,
+            3, 2, 0, 127, 0, 0, 2, 0x80, 0x80, 2, 0, 10, 0, 1, 11, 0x70, 0x70, 0, 3, 2, 1, 0, 0,
+        ];
+        let (return_route, body) = Message::decode_return_route(&u).unwrap();
+        assert_eq!(
+            return_route.addresses,
+            vec![
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+                    0x8080
+                ),
+                Address::UdpAddress(
+                    AddressType::Udp,
+                    IpAddr::V4(Ipv4Addr::new(10, 0, 1, 11)),
+                    0x7070
+                ),
+                Address::local(0x00010203),
+            ]
+        );
+        assert_eq!(body, &[0]);
+
+        // The onward route is stepped over rather than decoded, so a worker name that isn't
+        // UTF-8 there goes unnoticed, and other formats and hints are followed
+        let mut msg = Message {
+            onward_route: Route {
+                addresses: vec![Address::named_local("x"), Address::udp_loopback(80)],
+            },
+            return_route: Route {
+                addresses: vec![Address::udp_loopback(4000)],
+            },
+            hints: vec![(0x1300, vec![1])],
+            message_body: vec![0xaa],
+            ..Default::default()
+        };
+        for format in [
+            WireFormat::default(),
+            WireFormat {
+                varint_ports: true,
+                ..Default::default()
+            },
+            WireFormat {
+                fixed_u16: true,
+                ..Default::default()
+            },
+        ] {
+            msg.onward_route.addresses[0] = Address::named_local("x");
+            let mut u = vec![];
+            Message::encode_with_format(&msg, &mut u, format).unwrap();
+            let name = u.iter().position(|b| *b == b'x').unwrap();
+            u[name] = 0xff;
+            assert_eq!(Message::decode(&u).err(), Some(MessageError::InvalidUtf8));
+            let (return_route, body) = Message::decode_return_route(&u).unwrap();
+            assert_eq!(return_route, msg.return_route);
+            assert_eq!(body, &[0xaa]);
+        }
+    }
+
+    #[test]
+    fn route_delta() {
+        let mut route = Route {
+            addresses: vec![
+                Address::udp_loopback(0x1010),
+                Address::udp_loopback(0x2020),
+                Address::local(7),
+            ],
+        };
+        let deltas = [
+            RouteDelta::RemoveFront(1),
+            RouteDelta::PushFront(vec![Address::udp_loopback(0x3030)]),
+        ];
+        let mut u = vec![];
+        for delta in deltas.iter() {
+            RouteDelta::encode(delta, &mut u).unwrap();
+        }
+        assert_eq!(&u[..3], &[0, 1, 1]);
+
+        let mut w = &u[..];
+        while !w.is_empty() {
+            let (delta, x) = RouteDelta::decode(w).unwrap();
+            route.apply_delta(&delta).unwrap();
+            w = x;
+        }
+        assert_eq!(
+            route.addresses,
+            vec![
+                Address::udp_loopback(0x3030),
+                Address::udp_loopback(0x2020),
+                Address::local(7),
+            ]
+        );
+
+        let replace = RouteDelta::Replace(Route {
+            addresses: vec![Address::local(1)],
+        });
+        let mut u = vec![];
+        RouteDelta::encode(&replace, &mut u).unwrap();
+        assert_eq!(RouteDelta::decode(&u), Ok((replace.clone(), &[][..])));
+        route.apply_delta(&replace).unwrap();
+        assert_eq!(route.addresses, vec![Address::local(1)]);
+
+        assert_eq!(
+            route.apply_delta(&RouteDelta::RemoveFront(2)),
+            Err(MessageError::EmptyRoute)
+        );
+        assert_eq!(route.addresses, vec![Address::local(1)]);
+        assert_eq!(
+            RouteDelta::decode(&[3]),
+            Err(MessageError::UnknownRouteDelta(3))
+        );
+
+        // Replacing is held to MAX_ROUTE_HOPS as pushing is
+        let long = RouteDelta::Replace(Route {
+            addresses: vec![Address::local(2); MAX_ROUTE_HOPS + 1],
+        });
+        assert_eq!(
+            route.apply_delta(&long),
+            Err(MessageError::RouteTooLong(MAX_ROUTE_HOPS + 1))
+        );
+        assert_eq!(route.addresses, vec![Address::local(1)]);
+
+        // Nothing is left behind when a delta can't be encoded
+        let mut u = vec![0xaa];
+        for delta in [
+            long,
+            RouteDelta::PushFront(vec![Address::local(2); MAX_ROUTE_HOPS + 1]),
+            RouteDelta::PushFront(vec![Address::local(2), Address::Custom(0x40, vec![])]),
+        ]
+        .iter()
+        {
+            assert!(RouteDelta::encode(delta, &mut u).is_err());
+            assert_eq!(u, [0xaa]);
         }
     }
 }
@@ -35,7 +35,8 @@ pub mod router {
             let mut address_type: u8 = 0;
             let address: Address;
             if !m.onward_route.addresses.is_empty() {
-                address = m.onward_route.addresses[0];
+                // Aliases route like the address they wrap
+                address = m.onward_route.addresses[0].unaliased().clone();
                 match address {
                     Address::LocalAddress(t, _0) => {
                         address_type = t as u8;
@@ -46,6 +47,16 @@ pub mod router {
                     Address::TcpAddress(t, _0, _1) => {
                         address_type = t as u8;
                     }
+                    Address::Tunnel(_) => {
+                        address_type = AddressType::Tunnel as u8;
+                    }
+                    Address::NamedLocal(t, _) => {
+                        address_type = t as u8;
+                    }
+                    Address::Custom(t, _) => {
+                        address_type = t;
+                    }
+                    Address::Aliased(_, _) => unreachable!("unaliased() strips aliases"),
                 }
             }
             match &self.registry[address_type as usize] {
@@ -129,6 +140,7 @@ mod tests {
             onward_route,
             return_route,
             message_body,
+            ..Default::default()
         });
         let mut router: Router = Router::new();
         let udp_socket = UdpSocket::bind("127.0.0.1:4050").expect("couldn't bind to address");